@@ -23,12 +23,23 @@
     TYPE(LONG, 1) \
     TYPE(TIME, 2) \
     TYPE(DOUBLE, 3) \
+    TYPE(BOOL, 4) \
+    TYPE(DECIMAL, 5) \
 
 #define _TYPE_CTYPE_TEXT    const char *
 #define _TYPE_CTYPE_TIME    long
 #define _TYPE_CTYPE_LONG    long
 #define _TYPE_CTYPE_DOUBLE  double
-#define _TYPE_CTYPE(t) PASTE(_TYPE_CTYPE_, t)
+#define _TYPE_CTYPE_BOOL    bool
+#define _TYPE_CTYPE_DECIMAL long
+
+// `DECIMAL(scale)` expands to `DECIMAL, .scale = (scale)`; the helpers below take
+// the type name from the front and pass any trailing column options through.
+#define DECIMAL(_scale) DECIMAL, .scale = (_scale)
+#define _TYPE_CTYPE(...) _TYPE_CTYPE2(__VA_ARGS__, )
+#define _TYPE_CTYPE2(t, ...) PASTE(_TYPE_CTYPE_, t)
+#define _TYPE_INIT(...) _TYPE_INIT2(__VA_ARGS__, )
+#define _TYPE_INIT2(t, ...) .cell_type = PASTE(TYPE_, t), __VA_ARGS__
 
 enum cell_type {
     #define TYPE(t, n) PASTE(TYPE_, t) = n,
@@ -49,32 +60,103 @@ static inline const char * strtype(enum cell_type type) {
     return "(unknown)";
 }
 
+enum on_error {
+    ON_ERROR_EMPTY = 0,
+    ON_ERROR_DEFAULT = 1,
+    ON_ERROR_ABORT = 2,
+    ON_ERROR_WARN = 3,
+};
+
+struct format {
+    int16_t precision;
+    bool has_precision;
+    bool thousands;
+    bool percent;
+    bool scientific;
+    bool hex;
+};
+
 struct column {
     const char * const name;
     enum cell_type cell_type;
     int16_t grid_width;
+    enum on_error on_error;
+    const char * default_value;
+    int16_t scale;
+    struct format format;
 };
 
 #define GRID_WIDTH(n)   (n)
 #define GRID_HIDDEN     -1
 #define GRID_AUTO       0
 
+// Optional COLUMN arguments
+#define DEFAULT(v)      .default_value = (v)
+#define ON_ERROR(p)     .on_error = PASTE(ON_ERROR_, p)
+#define SCALE(n)        .scale = (n)
+
+// Display formats, also optional COLUMN arguments
+#define FMT_PRECISION(n) .format.precision = (n), .format.has_precision = true
+#define FMT_THOUSANDS   .format.thousands = true
+#define FMT_PERCENT     .format.percent = true
+#define FMT_SCIENTIFIC  .format.scientific = true
+#define FMT_HEX         .format.hex = true
+
 struct row {
-    #define COLUMN(_NAME, _TYPE, _GRID_WIDTH) union { _TYPE_CTYPE(_TYPE) _NAME; uint64_t PASTE(_placeholder_, _NAME); };
+    #define COLUMN(_NAME, _TYPE, _GRID_WIDTH, ...) union { _TYPE_CTYPE(_TYPE) _NAME; uint64_t PASTE(_placeholder_, _NAME); };
     COLUMN_LIST
     #undef COLUMN
     struct {
-        #define COLUMN(_NAME, _TYPE, _GRID_WIDTH) bool _NAME;
+        #define COLUMN(_NAME, _TYPE, _GRID_WIDTH, ...) bool _NAME;
         COLUMN_LIST
         #undef COLUMN
     } _empty;
 };
 
 struct api;
+struct map;
+struct digest;
+struct distinct;
+struct partition;
 struct api {
     int8_t (* const next)(struct api * api, void * row_out, bool * empty_out);
     int8_t (* const grid)(struct api * api, const void * row, const bool * empty);
     void (* const write)(struct api * api, const char * str);
+    long (* const row_number)(struct api * api);
+    const char * (* const last_error)(struct api * api);
+    void * (* const arena_alloc)(struct api * api, size_t size);
+    const char * (* const arena_strdup)(struct api * api, const char * str);
+    int8_t (* const set)(struct api * api, const char * key, const char * value);
+    int8_t (* const lookup)(struct api * api, const char * name, const struct column * columns, size_t columns_count,
+                            const char * key, void * row_out, bool * empty_out);
+    int8_t (* const regex_match)(struct api * api, const char * pattern, const char * text);
+    const char * (* const regex_capture)(struct api * api, const char * pattern, const char * text, size_t group);
+    const char * (* const regex_replace)(struct api * api, const char * pattern, const char * text,
+                                         const char * replacement);
+    const char ** (* const split)(struct api * api, const char * text, const char * separator, size_t * count_out);
+    const char * (* const convert)(struct api * api, const char * text, int8_t conversion);
+    struct map * (* const map_new)(struct api * api);
+    int8_t (* const map_update)(struct api * api, struct map * map, bool text_key, const char * text, long number,
+                                int8_t op, long value, long * value_out);
+    size_t (* const map_len)(struct api * api, const struct map * map);
+    int8_t (* const map_entry)(struct api * api, const struct map * map, size_t index, const char ** text_out,
+                               long * number_out, long * value_out);
+    struct digest * (* const digest_new)(struct api * api);
+    void (* const digest_add)(struct api * api, struct digest * digest, double value);
+    double (* const digest_quantile)(struct api * api, struct digest * digest, double q);
+    uint64_t (* const digest_count)(struct api * api, const struct digest * digest);
+    struct distinct * (* const distinct_new)(struct api * api);
+    void (* const distinct_add)(struct api * api, struct distinct * distinct, bool text_key, const char * text,
+                                long number);
+    long (* const distinct_count)(struct api * api, const struct distinct * distinct);
+    void (* const history)(struct api * api, size_t rows);
+    int8_t (* const lag)(struct api * api, size_t k, void * row_out, bool * empty_out);
+    int8_t (* const lead)(struct api * api, size_t k, void * row_out, bool * empty_out);
+    struct partition * (* const partition_new)(struct api * api);
+    void * (* const partition_state)(struct api * api, struct partition * partition, bool text_key, const char * text,
+                                     long number, size_t size);
+    void * (* const partition_entry)(struct api * api, const struct partition * partition, size_t index,
+                                     const char ** text_out, long * number_out);
 
     char _rust_owned_data[];
 };
@@ -82,14 +164,36 @@ struct api {
 // Exports
 void run(struct api * api);
 
-#define COLUMN(_NAME, _TYPE, _GRID_WIDTH) (struct column) { .name = STRINGIFY(_NAME), .cell_type = PASTE(TYPE_, _TYPE), .grid_width = _GRID_WIDTH},
+#define COLUMN(_NAME, _TYPE, _GRID_WIDTH, ...) (struct column) { .name = STRINGIFY(_NAME), _TYPE_INIT(_TYPE) .grid_width = _GRID_WIDTH, __VA_ARGS__ },
 const struct column columns[] = {
     COLUMN_LIST
 };
 #undef COLUMN
 const size_t columns_count = sizeof(columns) / sizeof(columns[0]);
 
+// Lookups loaded with `--lookup name=file.csv:key`. Each name in LOOKUP_LIST has a
+// `LOOKUP_<name>(COLUMN)` list of the columns to read, which defines `struct lookup_<name>`
+// with the same layout as `struct row`.
+#ifdef LOOKUP_LIST
+#define _LOOKUP_VALUE(_NAME, _TYPE, _GRID_WIDTH, ...) union { _TYPE_CTYPE(_TYPE) _NAME; uint64_t PASTE(_placeholder_, _NAME); };
+#define _LOOKUP_EMPTY(_NAME, _TYPE, _GRID_WIDTH, ...) bool _NAME;
+#define _LOOKUP_COLUMN(_NAME, _TYPE, _GRID_WIDTH, ...) (struct column) { .name = STRINGIFY(_NAME), _TYPE_INIT(_TYPE) .grid_width = _GRID_WIDTH, __VA_ARGS__ },
+#define LOOKUP(_LOOKUP) \
+    struct PASTE(lookup_, _LOOKUP) { \
+        PASTE(LOOKUP_, _LOOKUP)(_LOOKUP_VALUE) \
+        struct { PASTE(LOOKUP_, _LOOKUP)(_LOOKUP_EMPTY) } _empty; \
+    }; \
+    static const struct column PASTE(_lookup_columns_, _LOOKUP)[] = { PASTE(LOOKUP_, _LOOKUP)(_LOOKUP_COLUMN) };
+LOOKUP_LIST
+#undef LOOKUP
+#endif
+
 // Functions to use inside script
+
+// Read the next row into `row`; false at the end of the input. TEXT values stay valid for
+// the rest of the run, except with compressed input, which isn't all kept in memory: then
+// they're valid until the `api_next` after the next one, so a copy of the previous row
+// can still be used.
 static bool
 api_next(struct api * const api, struct row * const row) {
     return api->next(api, row, (bool *) &row->_empty);
@@ -100,6 +204,398 @@ api_grid(struct api * const api, struct row const * const row) {
     return api->grid(api, row, (bool *) &row->_empty);
 }
 
+// Input line number of the row last returned by `api_next`
+static inline long
+api_row_number(struct api * const api) {
+    return api->row_number(api);
+}
+
+// Description of the last problem reading the current row, or NULL if there was none
+static inline const char *
+api_last_error(struct api * const api) {
+    return api->last_error(api);
+}
+
+// Memory from the arena stays valid until `run` returns, and is freed by livid afterwards.
+// Use it for strings assigned to TEXT columns that must outlive a local buffer.
+static inline void *
+api_alloc(struct api * const api, size_t size) {
+    return api->arena_alloc(api, size);
+}
+
+static inline const char *
+api_strdup(struct api * const api, const char * const str) {
+    return api->arena_strdup(api, str);
+}
+
+static inline const char *
+api_strndup(struct api * const api, const char * const str, size_t n) {
+    size_t len = strnlen(str, n);
+    char * buf = api_alloc(api, len + 1);
+    memcpy(buf, str, len);
+    return buf;
+}
+
+static inline const char *
+api_sprintf(struct api * const api, const char * const fmt, ...) {
+    va_list vargs;
+    va_start(vargs, fmt);
+    int len = vsnprintf(NULL, 0, fmt, vargs);
+    va_end(vargs);
+    if (len < 0) return NULL;
+
+    char * buf = api_alloc(api, (size_t) len + 1);
+    va_start(vargs, fmt);
+    vsnprintf(buf, (size_t) len + 1, fmt, vargs);
+    va_end(vargs);
+    return buf;
+}
+
+// Change a preview setting for this run: "limit", "offset", "tail", "max_width", "precision"
+// or "renderer" ("ascii", "unicode", "markdown", "csv" or "expanded").
+// Settings uncommented in the workspace's `settings` file take precedence.
+static inline bool
+api_set(struct api * const api, const char * const key, const char * const value) {
+    return api->set(api, key, value);
+}
+
+// Fill `out`, a `struct lookup_<name>`, with the row of lookup `name` whose key column
+// is the string `key`. Returns false, leaving `out` as it was, if there's no such row.
+#define api_lookup(api, name, key, out) \
+    ((bool) (api)->lookup((api), STRINGIFY(name), PASTE(_lookup_columns_, name), \
+                          sizeof(PASTE(_lookup_columns_, name)) / sizeof(struct column), \
+                          (key), (out), (bool *) &(out)->_empty))
+
+// Like `api_lookup`, for keys that are numbers
+#define api_lookup_long(api, name, key, out) \
+    api_lookup(api, name, _api_long_key((char [24]) {0}, (key)), out)
+
+static inline const char *
+_api_long_key(char * const buf, long key) {
+    snprintf(buf, 24, "%ld", key);
+    return buf;
+}
+
+// String helpers. Patterns use the syntax of Rust's `regex` crate and are compiled once
+// per run; a pattern that doesn't compile is reported in the log. Strings returned are
+// allocated in the arena.
+
+// 1 if `pattern` matches somewhere in `text`, 0 if not, -1 if the pattern is invalid
+static inline int
+api_regex_match(struct api * const api, const char * const pattern, const char * const text) {
+    return api->regex_match(api, pattern, text);
+}
+
+// Capture group `group` of the first match (0 for the whole match), or NULL
+static inline const char *
+api_regex_capture(struct api * const api, const char * const pattern, const char * const text, size_t group) {
+    return api->regex_capture(api, pattern, text, group);
+}
+
+// `text` with every match replaced; `$1` or `${name}` in `replacement` expand to captures.
+// NULL if the pattern is invalid.
+static inline const char *
+api_regex_replace(struct api * const api, const char * const pattern, const char * const text,
+                  const char * const replacement) {
+    return api->regex_replace(api, pattern, text, replacement);
+}
+
+// Split `text` on each `separator`, or on runs of whitespace if it's "". Returns a
+// NULL-terminated array, and sets `*count` unless it's NULL.
+static inline const char **
+api_split(struct api * const api, const char * const text, const char * const separator, size_t * const count) {
+    return api->split(api, text, separator, count);
+}
+
+enum conversion {
+    CONVERT_UPPER = 0,
+    CONVERT_LOWER = 1,
+    CONVERT_TRIM = 2,
+};
+
+static inline const char *
+api_upper(struct api * const api, const char * const text) {
+    return api->convert(api, text, CONVERT_UPPER);
+}
+
+static inline const char *
+api_lower(struct api * const api, const char * const text) {
+    return api->convert(api, text, CONVERT_LOWER);
+}
+
+// `text` without leading and trailing whitespace
+static inline const char *
+api_trim(struct api * const api, const char * const text) {
+    return api->convert(api, text, CONVERT_TRIM);
+}
+
+static inline bool
+str_starts_with(const char * const text, const char * const prefix) {
+    return strncmp(text, prefix, strlen(prefix)) == 0;
+}
+
+static inline bool
+str_ends_with(const char * const text, const char * const suffix) {
+    size_t text_len = strlen(text);
+    size_t suffix_len = strlen(suffix);
+    return text_len >= suffix_len && strcmp(text + text_len - suffix_len, suffix) == 0;
+}
+
+// Hash maps from strings or numbers to `long`s, and sets, which are maps whose values
+// aren't used. They belong to the run, and are freed when `run` returns. Functions
+// ending in `_long` take number keys; a map can hold both kinds. A NULL string key, like
+// a missing field, is the same as "". Entries are iterated in the order they were first
+// inserted:
+//
+//     const char * key; long count;
+//     for (size_t i = 0; api_map_entry(api, counts, i, &key, NULL, &count); i++) ...
+
+enum map_op {
+    MAP_GET = 0,
+    MAP_PUT = 1,
+    MAP_ADD = 2,
+};
+
+static inline struct map *
+api_map_new(struct api * const api) {
+    return api->map_new(api);
+}
+
+// Set `*value` to the value of `key`; false if it isn't in the map
+static inline bool
+api_map_get(struct api * const api, struct map * const map, const char * const key, long * const value) {
+    return api->map_update(api, map, true, key, 0, MAP_GET, 0, value);
+}
+
+static inline bool
+api_map_get_long(struct api * const api, struct map * const map, long key, long * const value) {
+    return api->map_update(api, map, false, NULL, key, MAP_GET, 0, value);
+}
+
+// Set the value of `key`, inserting it if needed. The key is copied.
+static inline void
+api_map_put(struct api * const api, struct map * const map, const char * const key, long value) {
+    api->map_update(api, map, true, key, 0, MAP_PUT, value, NULL);
+}
+
+static inline void
+api_map_put_long(struct api * const api, struct map * const map, long key, long value) {
+    api->map_update(api, map, false, NULL, key, MAP_PUT, value, NULL);
+}
+
+// Add `delta` to the value of `key`, which starts at 0, and return the new value
+static inline long
+api_map_add(struct api * const api, struct map * const map, const char * const key, long delta) {
+    long value = 0;
+    api->map_update(api, map, true, key, 0, MAP_ADD, delta, &value);
+    return value;
+}
+
+static inline long
+api_map_add_long(struct api * const api, struct map * const map, long key, long delta) {
+    long value = 0;
+    api->map_update(api, map, false, NULL, key, MAP_ADD, delta, &value);
+    return value;
+}
+
+static inline size_t
+api_map_len(struct api * const api, const struct map * const map) {
+    return api->map_len(api, map);
+}
+
+// The `index`th entry. `*text_key` is NULL for number keys; any of the outputs may be
+// NULL. False past the last entry.
+static inline bool
+api_map_entry(struct api * const api, const struct map * const map, size_t index, const char ** const text_key,
+              long * const number_key, long * const value) {
+    return api->map_entry(api, map, index, text_key, number_key, value);
+}
+
+// Insert `key` into a set made with `api_map_new`; false if it was already there
+static inline bool
+api_set_insert(struct api * const api, struct map * const set, const char * const key) {
+    return !api->map_update(api, set, true, key, 0, MAP_ADD, 0, NULL);
+}
+
+static inline bool
+api_set_insert_long(struct api * const api, struct map * const set, long key) {
+    return !api->map_update(api, set, false, NULL, key, MAP_ADD, 0, NULL);
+}
+
+static inline bool
+api_set_contains(struct api * const api, struct map * const set, const char * const key) {
+    return api->map_update(api, set, true, key, 0, MAP_GET, 0, NULL);
+}
+
+static inline bool
+api_set_contains_long(struct api * const api, struct map * const set, long key) {
+    return api->map_update(api, set, false, NULL, key, MAP_GET, 0, NULL);
+}
+
+// Approximate statistics, for when keeping every value would be too much. Like maps,
+// they belong to the run. Results are plain numbers, to print or put in a grid row:
+//
+//     row->p99 = api_digest_quantile(api, latency, 0.99);
+//     row->users = api_distinct_count(api, users);
+
+// Quantiles with a t-digest, accurate to well under 1% of rank, and exact for the
+// smallest and largest values
+static inline struct digest *
+api_digest_new(struct api * const api) {
+    return api->digest_new(api);
+}
+
+// NaN and infinities are ignored
+static inline void
+api_digest_add(struct api * const api, struct digest * const digest, double value) {
+    api->digest_add(api, digest, value);
+}
+
+// The value below which a fraction `q` of the values fall; 0.5 is the median. NaN if
+// nothing has been added.
+static inline double
+api_digest_quantile(struct api * const api, struct digest * const digest, double q) {
+    return api->digest_quantile(api, digest, q);
+}
+
+static inline size_t
+api_digest_count(struct api * const api, const struct digest * const digest) {
+    return (size_t) api->digest_count(api, digest);
+}
+
+// Distinct counts with HyperLogLog, within about 1% using 16KB however many values are
+// added. Strings and numbers are counted apart, so "1" and 1 are two values.
+static inline struct distinct *
+api_distinct_new(struct api * const api) {
+    return api->distinct_new(api);
+}
+
+static inline void
+api_distinct_add(struct api * const api, struct distinct * const distinct, const char * const key) {
+    api->distinct_add(api, distinct, true, key, 0);
+}
+
+static inline void
+api_distinct_add_long(struct api * const api, struct distinct * const distinct, long key) {
+    api->distinct_add(api, distinct, false, NULL, key);
+}
+
+static inline long
+api_distinct_count(struct api * const api, const struct distinct * const distinct) {
+    return api->distinct_count(api, distinct);
+}
+
+// Window functions. Rows from `api_lag` and `api_lead` are as `api_next` returned them,
+// before any changes the script made; their text stays valid until the next `api_next`.
+//
+//     api_history(api, 1);
+//     while (api_next(api, row)) {
+//         if (api_lag(api, 1, prev)) row->delta = row->value - prev->value;
+//         ...
+
+// Keep the last `rows` rows before the current one for `api_lag`. Call it before the
+// first `api_next`; rows read earlier aren't kept.
+static inline void
+api_history(struct api * const api, size_t rows) {
+    api->history(api, rows);
+}
+
+// Fill `out` with the row `k` rows before the one `api_next` returned last; false if
+// there's no such row or it wasn't kept
+static inline bool
+api_lag(struct api * const api, size_t k, struct row * const out) {
+    return api->lag(api, k, out, (bool *) &out->_empty);
+}
+
+// Fill `out` with the row `k` rows after the one `api_next` returned last, reading ahead
+// as needed; false past the end of the input
+static inline bool
+api_lead(struct api * const api, size_t k, struct row * const out) {
+    return api->lead(api, k, out, (bool *) &out->_empty);
+}
+
+// Running count, sum, mean, minimum and maximum; start from `(struct running) {0}`
+struct running {
+    long count;
+    double sum;
+    double min;
+    double max;
+};
+
+static inline void
+running_add(struct running * const r, double value) {
+    if (r->count == 0 || value < r->min) r->min = value;
+    if (r->count == 0 || value > r->max) r->max = value;
+    r->count++;
+    r->sum += value;
+}
+
+static inline double
+running_mean(const struct running * const r) {
+    return r->count ? r->sum / (double) r->count : 0.0;
+}
+
+// Sum and mean of the last `size` values added
+struct moving {
+    size_t size;
+    size_t count;
+    double sum;
+    double * values;
+};
+
+static inline struct moving
+api_moving_new(struct api * const api, size_t size) {
+    return (struct moving) { .size = size, .values = api_alloc(api, size * sizeof(double)) };
+}
+
+static inline void
+moving_add(struct moving * const m, double value) {
+    if (m->size == 0) return;
+    double * const slot = &m->values[m->count % m->size];
+    if (m->count >= m->size) m->sum -= *slot;
+    *slot = value;
+    m->sum += value;
+    m->count++;
+}
+
+static inline double
+moving_mean(const struct moving * const m) {
+    size_t n = m->count < m->size ? m->count : m->size;
+    return n ? m->sum / (double) n : 0.0;
+}
+
+// State kept per key, such as per user, for windows partitioned by a column. Each key
+// gets `size` bytes of zeroed memory from the arena the first time it's seen; use the
+// same size for every key of a partition. Like maps, a NULL string key is the same as "".
+//
+//     struct session { long last_seen; long id; };
+//     struct session * s = api_partition_state(api, sessions, row->user, sizeof *s);
+//     if (row->time - s->last_seen > 1800) s->id++;
+//     s->last_seen = row->time;
+
+static inline struct partition *
+api_partition_new(struct api * const api) {
+    return api->partition_new(api);
+}
+
+static inline void *
+api_partition_state(struct api * const api, struct partition * const partition, const char * const key, size_t size) {
+    return api->partition_state(api, partition, true, key, 0, size);
+}
+
+static inline void *
+api_partition_state_long(struct api * const api, struct partition * const partition, long key, size_t size) {
+    return api->partition_state(api, partition, false, NULL, key, size);
+}
+
+// The state of the `index`th key, in the order keys were first seen, or NULL past the
+// last one. `*text_key` is NULL for number keys; either output may be NULL.
+static inline void *
+api_partition_entry(struct api * const api, const struct partition * const partition, size_t index,
+                    const char ** const text_key, long * const number_key) {
+    return api->partition_entry(api, partition, index, text_key, number_key);
+}
+
 #define printf(...) api_printf(api, ## __VA_ARGS__)
 static void
 api_printf(struct api * const api, const char * const fmt, ...) {
@@ -1,5 +1,16 @@
-// TEXT, LONG, TIME, DOUBLE
+// TEXT, LONG, TIME, DOUBLE, BOOL, DECIMAL(2)
 // GRID_AUTO, GRID_HIDDEN, GRID_WIDTH(12)
+// Optional: DEFAULT("0"), ON_ERROR(EMPTY | DEFAULT | ABORT | WARN)
+//           FMT_PRECISION(2), FMT_THOUSANDS, FMT_PERCENT, FMT_SCIENTIFIC, FMT_HEX
+// Where each row came from: COLUMN(_source_file, TEXT, ...), COLUMN(_line, LONG, ...)
+// With --lookup users=users.csv:id: struct lookup_users user; api_lookup_long(api, users, row->id, &user)
+// api_regex_match, api_regex_capture, api_regex_replace, api_split, api_upper, api_lower, api_trim
+// struct map * counts = api_map_new(api); api_map_add(api, counts, key, 1); api_set_insert(api, seen, key)
+// struct digest * d = api_digest_new(api); api_digest_add(api, d, x); api_digest_quantile(api, d, 0.5)
+// struct distinct * u = api_distinct_new(api); api_distinct_add(api, u, key); api_distinct_count(api, u)
+// api_history(api, 1); api_lag(api, 1, prev); api_lead(api, 1, next); struct running r = {0}; running_add(&r, x)
+// struct partition * p = api_partition_new(api); struct state * s = api_partition_state(api, p, key, sizeof *s)
+// api_set(api, "offset", "100"), api_set(api, "tail", "true"), api_set(api, "renderer", "expanded")
 #include "livid.h"
 const size_t grid_rows_limit = 20;
 
@@ -0,0 +1,129 @@
+//! Requests sent to a running livid, one per line. They arrive on the control FIFO,
+//! written by the commands defined in the generated vimrc, and on the control socket,
+//! where each request is answered with a line of JSON.
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use output::{Renderer, ViewSettings};
+
+#[derive(Debug)]
+pub enum Request {
+    /// Run the script again, without recompiling it
+    Rerun,
+    /// Override a preview setting for the rest of the session
+    Set(String, String),
+    /// Run the script without a row limit, writing every row to a file
+    Export(PathBuf),
+    /// Switch to a different input file
+    Input(PathBuf),
+    /// Read the current input file again from the start
+    ReloadInput,
+    /// Report the state of the last run, without running again
+    Status,
+    /// Reload another vim, started by `livid attach`, along with the session's own
+    Attach(String),
+    Detach(String),
+    Quit,
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, args) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let request = match name {
+            "rerun" => Request::Rerun,
+            "status" => Request::Status,
+            "quit" => Request::Quit,
+            "reload-input" => Request::ReloadInput,
+            "set" => match args.find(char::is_whitespace) {
+                Some(i) => Request::Set(args[..i].to_string(), args[i..].trim().to_string()),
+                None => Request::Set(args.to_string(), String::new()),
+            },
+            // Shorthands for `set`
+            "limit" | "offset" | "tail" | "renderer" => Request::Set(name.to_string(), args.to_string()),
+            "set-limit" => Request::Set("limit".to_string(), args.to_string()),
+            "export" | "input" if args.is_empty() => return Err(format!("{} needs a path", name)),
+            "attach" | "detach" if args.is_empty() => return Err(format!("{} needs a server name", name)),
+            "attach" => Request::Attach(args.to_string()),
+            "detach" => Request::Detach(args.to_string()),
+            "export" => Request::Export(PathBuf::from(args)),
+            "input" => Request::Input(PathBuf::from(args)),
+            _ => return Err(format!("unknown request {:?}", name)),
+        };
+        if let Request::Set(ref key, ref value) = request {
+            ViewSettings::default().set(key, value)?;
+        }
+        Ok(request)
+    }
+}
+
+/// Renderer used when exporting to `path`, based on its extension
+pub fn export_renderer(path: &Path) -> Renderer {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("md") => Renderer::Markdown,
+        Some("txt") => Renderer::Ascii,
+        _ => Renderer::Csv,
+    }
+}
+
+/// Splits what's read from a non-blocking FIFO or socket into lines
+pub struct LineReader<R> {
+    pub source: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn new(source: R) -> Self {
+        LineReader { source, buffer: vec![] }
+    }
+
+    /// Read what's available without blocking and append any complete lines to `lines`.
+    /// Returns `false` once the other end has closed.
+    pub fn read_lines(&mut self, lines: &mut Vec<String>) -> io::Result<bool> {
+        let mut chunk = [0; 1024];
+        let open = loop {
+            match self.source.read(&mut chunk) {
+                Ok(0) => break false,
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        while let Some(i) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=i).collect();
+            lines.push(String::from_utf8_lossy(&line).trim().to_string());
+        }
+        Ok(open)
+    }
+}
+
+/// Quote a string for JSON
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn json_error(error: &str) -> String {
+    format!("{{\"ok\": false, \"error\": {}}}", json_string(error))
+}
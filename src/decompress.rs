@@ -0,0 +1,144 @@
+//! Input compressed with gzip, zstd, bzip2 or xz, recognised by its first bytes rather
+//! than its name, so compressed data piped to livid is read too.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader};
+
+use bzip2::bufread::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Compression::None => "uncompressed",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+            Compression::Xz => "xz",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Look at the first bytes of `reader` and decompress what's read from it if they're
+/// the magic number of a known format. Concatenated streams, as written by `cat a.gz
+/// b.gz` or parallel compressors, are read as one.
+pub fn decompress<R: BufRead + 'static>(mut reader: R) -> io::Result<(Box<dyn BufRead>, Compression)> {
+    let compression = Compression::detect(reader.fill_buf()?);
+    let reader: Box<dyn BufRead> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+        Compression::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+        Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+    };
+    Ok((reader, compression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Write};
+
+    use bzip2::write::BzEncoder;
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+
+    const CSV: &[u8] = b"a,b\n1,2\n3,4\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn compressed(compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::None => CSV.to_vec(),
+            Compression::Gzip => gzip(CSV),
+            Compression::Zstd => zstd::encode_all(CSV, 0).unwrap(),
+            Compression::Bzip2 => {
+                let mut encoder = BzEncoder::new(vec![], bzip2::Compression::default());
+                encoder.write_all(CSV).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Xz => {
+                let mut encoder = XzEncoder::new(vec![], 6);
+                encoder.write_all(CSV).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    fn read(data: Vec<u8>) -> (Vec<u8>, Compression) {
+        let (mut reader, compression) = decompress(Cursor::new(data)).unwrap();
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        (out, compression)
+    }
+
+    #[test]
+    fn magic_bytes_are_detected() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 0x08]), Compression::Gzip);
+        assert_eq!(Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]), Compression::Zstd);
+        assert_eq!(Compression::detect(b"BZh91AY"), Compression::Bzip2);
+        assert_eq!(Compression::detect(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]), Compression::Xz);
+        assert_eq!(Compression::detect(b"id,name\n"), Compression::None);
+        // Too short to be sure
+        assert_eq!(Compression::detect(&[0x1f]), Compression::None);
+        assert_eq!(Compression::detect(b"BZ"), Compression::None);
+        assert_eq!(Compression::detect(&[0xfd, b'7', b'z', b'X', b'Z']), Compression::None);
+        assert_eq!(Compression::detect(&[]), Compression::None);
+    }
+
+    #[test]
+    fn every_format_is_decompressed() {
+        for &compression in &[
+            Compression::None,
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Bzip2,
+            Compression::Xz,
+        ] {
+            assert_eq!(read(compressed(compression)), (CSV.to_vec(), compression), "{}", compression);
+        }
+    }
+
+    #[test]
+    fn concatenated_streams_are_read_as_one() {
+        let mut data = gzip(b"a,b\n1,2\n");
+        data.extend(gzip(b"3,4\n"));
+        assert_eq!(read(data), (CSV.to_vec(), Compression::Gzip));
+    }
+
+    #[test]
+    fn empty_input_is_uncompressed() {
+        assert_eq!(read(vec![]), (vec![], Compression::None));
+    }
+}
@@ -0,0 +1,134 @@
+//! Tables loaded from secondary files with `--lookup name=path:key` and indexed by their
+//! key column, so scripts can join rows against them with `api_lookup`.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::path::PathBuf;
+use std::slice;
+use std::str::FromStr;
+
+use super::{CColumn, Column, CsvInputFile, InputTable, Result};
+
+/// A `--lookup` argument
+#[derive(Debug)]
+pub struct LookupSpec {
+    name: String,
+    path: PathBuf,
+    key: String,
+}
+
+impl FromStr for LookupSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let usage = || format!("{:?} should look like name=file.csv:key", s);
+        let (name, rest) = s.split_once('=').ok_or_else(usage)?;
+        // The path may have colons of its own; the key is after the last one
+        let (path, key) = rest.rsplit_once(':').ok_or_else(usage)?;
+        let identifier = name.chars().enumerate().all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        if name.is_empty() || !identifier {
+            return Err(format!("lookup name {:?} must be a C identifier", name));
+        }
+        if path.is_empty() || key.is_empty() {
+            return Err(usage());
+        }
+        Ok(LookupSpec {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            key: key.to_string(),
+        })
+    }
+}
+
+pub struct Lookup {
+    pub name: String,
+    pub key: String,
+    pub columns: Vec<Column>,
+    rows: Vec<Vec<CString>>,
+    /// Row of each key; the first row wins when a key is repeated
+    index: HashMap<Vec<u8>, usize>,
+}
+
+impl Lookup {
+    /// Read the whole file, guessing column types from the first `infer_rows` rows
+    pub fn load(spec: &LookupSpec, delimiter: char, infer_rows: usize) -> Result<Lookup> {
+        let mut table = CsvInputFile::new(slice::from_ref(&spec.path), delimiter, true, None, None)?;
+        table.infer_types(infer_rows);
+        let key_index = table
+            .input_columns()
+            .iter()
+            .position(|c| c.name.to_bytes() == spec.key.as_bytes())
+            .ok_or_else(|| format!("{:?} has no column {:?} to use as the key", spec.path, spec.key))?;
+
+        let mut rows: Vec<Vec<CString>> = table.raw_cells.drain(..).map(|r| r.fields).collect();
+        while let Some(row) = table.reader.read_row() {
+            rows.push(row.fields);
+        }
+        let mut index = HashMap::with_capacity(rows.len());
+        let mut duplicates = 0;
+        for (i, row) in rows.iter().enumerate() {
+            if let Some(key) = row.get(key_index) {
+                if index.contains_key(key.to_bytes()) {
+                    duplicates += 1;
+                } else {
+                    index.insert(key.to_bytes().to_vec(), i);
+                }
+            }
+        }
+        println!("Lookup {}: {} rows from {:?}, keyed by {:?}", spec.name, rows.len(), spec.path, spec.key);
+        if duplicates > 0 {
+            println!("Lookup {}: {} repeated key(s); the first row for each is used", spec.name, duplicates);
+        }
+        Ok(Lookup {
+            name: spec.name.clone(),
+            key: spec.key.clone(),
+            columns: table.input_columns,
+            rows,
+            index,
+        })
+    }
+
+    /// The fields of the row with `key`
+    pub fn get(&self, key: &CStr) -> Option<&[CString]> {
+        self.index.get(key.to_bytes()).map(|&i| &self.rows[i][..])
+    }
+
+    /// Index of the field holding column `name`
+    pub fn field(&self, name: &CStr) -> Option<usize> {
+        self.columns.iter().position(|c| c.name.as_c_str() == name)
+    }
+}
+
+/// A lookup struct declared by a script, matched to the lookup it's filled from
+pub struct LookupBinding {
+    pub lookup: usize,
+    /// The struct's columns, each with the lookup's field it's read from
+    pub columns: Vec<(Column, Option<usize>)>,
+}
+
+impl LookupBinding {
+    /// Match `columns` to the lookup called `name`. Problems are reported once, when the
+    /// struct is first used; `None` if there's no such lookup.
+    pub fn new(lookups: &[Lookup], name: &str, columns: &[CColumn]) -> Option<LookupBinding> {
+        let index = match lookups.iter().position(|l| l.name == name) {
+            Some(index) => index,
+            None => {
+                println!("Unknown lookup {:?}; load it with --lookup {}=file.csv:key", name, name);
+                return None;
+            }
+        };
+        let lookup = &lookups[index];
+        let columns = columns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let column = Column::from_c(*c, i);
+                let field = lookup.field(&column.name);
+                if field.is_none() {
+                    println!("Lookup {} has no column {:?}; it's left empty", lookup.name, column.name);
+                }
+                (column, field)
+            }).collect();
+        Some(LookupBinding { lookup: index, columns })
+    }
+}
@@ -1,35 +1,69 @@
 #![allow(dead_code)]
+extern crate bzip2;
 extern crate dlopen;
 #[macro_use]
 extern crate dlopen_derive;
 use dlopen::wrapper::{Container, WrapperApi};
+extern crate flate2;
+extern crate glob;
 extern crate inotify;
 extern crate libc;
+extern crate nix;
+extern crate regex;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
 extern crate structopt;
 use structopt::StructOpt;
-
+extern crate unicode_width;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+extern crate xz2;
+extern crate zstd;
+
+mod control;
+use control::{json_error, json_string, LineReader, Request};
+mod decompress;
+use decompress::{decompress, Compression};
+mod lookup;
+use lookup::{Lookup, LookupBinding, LookupSpec};
+mod maps;
+use maps::Map;
+mod output;
+mod profile;
+use profile::Profile;
+mod session;
+mod sketches;
+use sketches::{Digest, Distinct};
+mod text;
+mod window;
+use window::{Partition, Window};
+use text::RegexCache;
+use session::{Session, DEFAULT_WORKSPACE};
+use output::{OutputBuffer, Renderer, RowAction, ViewSettings};
+
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{BufRead, Seek, SeekFrom, Write};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
 use std::path;
-use std::process::{Command, Stdio};
+use std::process::{self, Command, Stdio};
 use std::slice;
 use std::str::FromStr;
 use std::thread;
 use std::default::Default;
 use std::marker::PhantomData;
 use std::os::raw::c_char;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::os::unix::io::AsRawFd;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::time::{Duration, Instant};
-// TODO: non-zero default values for numerics
 // TODO: parse time, time fns
-// TODO: serialize stdin back out to workspace?
 
-type Result<T> = std::result::Result<T, Box<std::error::Error>>;
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -38,19 +72,73 @@ enum CellType {
     Long = 1,
     Time = 2,
     Double = 3,
+    Bool = 4,
+    Decimal = 5,
 }
 
 impl CellType {
-    fn upper_str(self: &Self) -> &'static str {
-        match *self {
+    fn upper_str(self) -> &'static str {
+        match self {
             CellType::Text => "TEXT",
             CellType::Long => "LONG",
             CellType::Time => "TIME",
             CellType::Double => "DOUBLE",
+            CellType::Bool => "BOOL",
+            CellType::Decimal => "DECIMAL",
         }
     }
 }
 
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_lowercase().as_str() {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a plain decimal number (no exponent) into an integer scaled by `10^scale`.
+/// Digits beyond the scale are only accepted if they're zero.
+fn parse_decimal(s: &str, scale: i16) -> Option<i64> {
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let (whole, fraction) = match digits.find('.') {
+        Some(i) => (&digits[..i], &digits[i + 1..]),
+        None => (digits, ""),
+    };
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    if !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let scale = scale as usize;
+    if fraction.len() > scale && fraction[scale..].bytes().any(|b| b != b'0') {
+        return None;
+    }
+    let mut value: i64 = 0;
+    let fraction_digits = fraction.bytes().chain(std::iter::repeat(b'0')).take(scale);
+    for b in whole.bytes().chain(fraction_digits) {
+        value = value.checked_mul(10)?.checked_add((b - b'0') as i64)?;
+    }
+    Some(if negative { -value } else { value })
+}
+
+fn format_decimal(value: i64, scale: i16) -> String {
+    let scale = scale as usize;
+    let digits = format!("{:0width$}", value.unsigned_abs(), width = scale + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - scale);
+    let sign = if value < 0 { "-" } else { "" };
+    if scale == 0 {
+        format!("{}{}", sign, whole)
+    } else {
+        format!("{}{}.{}", sign, whole, fraction)
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct CStrPtr<'v> {
@@ -59,7 +147,7 @@ struct CStrPtr<'v> {
 }
 
 impl<'a> CStrPtr<'a> {
-    fn from(s: &'a CString) -> CStrPtr<'a> {
+    fn from(s: &'a CStr) -> CStrPtr<'a> {
         CStrPtr {
             ptr: s.as_ptr(),
             phantom: PhantomData,
@@ -67,6 +155,16 @@ impl<'a> CStrPtr<'a> {
     }
 }
 
+/// What to do with an input value that can't be parsed as the column's type
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum OnError {
+    Empty = 0,
+    Default = 1,
+    Abort = 2,
+    Warn = 3,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 union CellValue<'v> {
@@ -74,14 +172,20 @@ union CellValue<'v> {
     long: i64,
     time: i64,
     double: f64,
+    boolean: bool,
+    decimal: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Column {
     name: CString,
     index: usize,
     cell_type: CellType,
     grid_width: i16,
+    on_error: OnError,
+    default_value: Option<CString>,
+    scale: i16,
+    format: CellFormat,
 }
 
 #[repr(C)]
@@ -90,43 +194,171 @@ struct CColumn {
     name: *const c_char,
     cell_type: CellType,
     grid_width: i16,
+    on_error: OnError,
+    default_value: *const c_char,
+    scale: i16,
+    format: CellFormat,
+}
+
+/// How a column's values are displayed in the grid; set with the `FMT_*` column options
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct CellFormat {
+    precision: i16,
+    has_precision: bool,
+    thousands: bool,
+    percent: bool,
+    scientific: bool,
+    hex: bool,
 }
 
 impl<'v> CellValue<'v> {
-    fn to_string(&self, t: CellType, empty: bool) -> String {
+    fn from_bool(b: bool) -> Self {
+        // Zero the whole value so C code reading the full 8 bytes sees 0/1
+        let mut value = CellValue { long: 0 };
+        value.boolean = b;
+        value
+    }
+
+    fn to_string(self, column: &Column, empty: bool, default_precision: Option<usize>) -> String {
         if empty {
-            String::from("")
+            return String::from("");
+        }
+        let format = &column.format;
+        let precision = if format.has_precision {
+            Some(format.precision.max(0) as usize)
         } else {
-            unsafe {
-                match t {
-                    CellType::Text => self.text.to_string(),
-                    CellType::Long => self.long.to_string(),
-                    CellType::Time => self.time.to_string(), // TODO
-                    CellType::Double => self.double.to_string(),
+            default_precision
+        };
+        let number = unsafe {
+            match column.cell_type {
+                CellType::Text => return self.text.to_string(),
+                CellType::Bool => return self.boolean.to_string(),
+                CellType::Long if format.hex => return format!("{:#x}", self.long),
+                CellType::Long if format.percent || format.scientific => {
+                    format_double(self.long as f64, format, precision)
+                }
+                CellType::Long => self.long.to_string(),
+                CellType::Time => self.time.to_string(), // TODO
+                CellType::Double => format_double(self.double, format, precision),
+                CellType::Decimal if format.percent || format.scientific => {
+                    let value = self.decimal as f64 / 10f64.powi(column.scale as i32);
+                    format_double(value, format, precision)
                 }
+                CellType::Decimal => format_decimal(self.decimal, column.scale),
             }
+        };
+        let number = if format.thousands && !format.scientific {
+            group_thousands(&number)
+        } else {
+            number
+        };
+        if format.percent {
+            number + "%"
+        } else {
+            number
         }
     }
 }
 
+fn format_double(value: f64, format: &CellFormat, precision: Option<usize>) -> String {
+    let value = if format.percent { value * 100.0 } else { value };
+    match (format.scientific, precision) {
+        (true, Some(p)) => format!("{:.*e}", p, value),
+        (true, None) => format!("{:e}", value),
+        (false, Some(p)) => format!("{:.*}", p, value),
+        (false, None) => format_double_default(value),
+    }
+}
+
+/// Shortest representation of `value` after rounding to 15 significant digits, which
+/// hides binary noise like `0.1 + 0.2 = 0.30000000000000004`
+fn format_double_default(value: f64) -> String {
+    if !value.is_finite() {
+        return value.to_string();
+    }
+    let rounded = f64::from_str(&format!("{:.14e}", value)).unwrap_or(value);
+    if rounded != 0.0 && (rounded.abs() >= 1e16 || rounded.abs() < 1e-6) {
+        format!("{:e}", rounded)
+    } else {
+        rounded.to_string()
+    }
+}
+
+/// Insert commas between groups of three digits in the integer part of a number
+fn group_thousands(number: &str) -> String {
+    let digits_start = number.find(|c: char| c.is_ascii_digit()).unwrap_or(number.len());
+    let digits_end = number[digits_start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(number.len(), |i| digits_start + i);
+    let digits = &number[digits_start..digits_end];
+    let mut out = String::from(&number[..digits_start]);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out.push_str(&number[digits_end..]);
+    out
+}
+
 impl Column {
     fn from_c(c: CColumn, index: usize) -> Self {
         Column {
             name: CString::from(unsafe { const_char_cstr(c.name) }),
-            index: index,
+            index,
             cell_type: c.cell_type,
             grid_width: c.grid_width,
+            on_error: c.on_error,
+            default_value: if c.default_value.is_null() {
+                None
+            } else {
+                Some(CString::from(unsafe { const_char_cstr(c.default_value) }))
+            },
+            // Scales beyond 18 digits don't fit in an i64
+            scale: c.scale.clamp(0, 18),
+            format: c.format,
         }
     }
-    fn empty_value<'c>(&'c self) -> Cell<'c, 'c> {
+    /// Type as written in a `COLUMN` definition, e.g. `DECIMAL(2)`
+    fn type_str(&self) -> String {
+        match self.cell_type {
+            CellType::Decimal => format!("DECIMAL({})", self.scale),
+            t => t.upper_str().to_string(),
+        }
+    }
+    fn null_value<'c>(&'c self) -> Cell<'c, 'c> {
         Cell {
             column: self,
             empty: true,
             value: CellValue { long: 0 },
         }
     }
-    fn parse_value<'v, 'c: 'v>(&'c self, v: &'v CString) -> Cell<'c, 'v> {
-        let op_value = match self.cell_type {
+    /// The cell used when the input has no value: the column default, if any, otherwise empty
+    fn empty_value<'c>(&'c self) -> Cell<'c, 'c> {
+        match self.default_value.as_ref().and_then(|d| self.parse_raw(d)) {
+            Some(value) => Cell {
+                column: self,
+                empty: false,
+                value,
+            },
+            None => self.null_value(),
+        }
+    }
+    /// Parse a raw input value; `None` if it isn't valid for the column's type
+    fn parse_value<'v, 'c: 'v>(&'c self, v: &'v CStr) -> Option<Cell<'c, 'v>> {
+        if v.to_bytes().is_empty() && (self.default_value.is_some() || self.cell_type != CellType::Text) {
+            return Some(self.empty_value());
+        }
+        self.parse_raw(v).map(|value| Cell {
+            column: self,
+            empty: false,
+            value,
+        })
+    }
+    fn parse_raw<'v>(&self, v: &'v CStr) -> Option<CellValue<'v>> {
+        match self.cell_type {
             CellType::Text => Some(CellValue {
                 text: CStrPtr::from(v),
             }),
@@ -145,15 +377,156 @@ impl Column {
                 .ok()
                 .and_then(|x| f64::from_str(x).ok())
                 .map(|x| CellValue { double: x }),
-        };
-        if let Some(value) = op_value {
-            Cell {
-                column: self,
-                empty: false,
-                value: value,
+            CellType::Bool => v
+                .to_str()
+                .ok()
+                .and_then(parse_bool)
+                .map(CellValue::from_bool),
+            CellType::Decimal => v
+                .to_str()
+                .ok()
+                .and_then(|x| parse_decimal(x, self.scale))
+                .map(|x| CellValue { decimal: x }),
+        }
+    }
+    /// Handle a value that failed to parse according to the column's `on_error` policy.
+    /// Returns `None` if the run should be aborted.
+    fn parse_error<'c>(&'c self, diagnostic: &Diagnostic) -> Option<Cell<'c, 'c>> {
+        match self.on_error {
+            OnError::Empty => Some(self.null_value()),
+            OnError::Default => Some(self.empty_value()),
+            OnError::Warn => {
+                println!("Warning: {}", diagnostic);
+                Some(self.empty_value())
+            }
+            OnError::Abort => {
+                println!("Error: {}; aborting run", diagnostic);
+                None
+            }
+        }
+    }
+}
+
+/// A problem found while reading the input: an unparsable value, a ragged row, or a file
+/// that couldn't be read to its end
+#[derive(Debug, Clone)]
+struct Diagnostic {
+    /// Set when reading more than one file
+    file: Option<String>,
+    line: usize,
+    column: Option<CString>,
+    raw: String,
+    reason: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}: ", file)?;
+        }
+        write!(f, "line {}", self.line)?;
+        if let Some(ref column) = self.column {
+            write!(f, ", column {:?}", column)?;
+        }
+        write!(f, ": {}", self.reason)?;
+        if !self.raw.is_empty() {
+            write!(f, " ({:?})", self.raw)?;
+        }
+        Ok(())
+    }
+}
+
+/// Initial contents of the workspace's `settings` file. Uncommented settings take
+/// precedence over the script's, and saving the file re-runs the script.
+const SETTINGS_TEMPLATE: &str = "\
+# limit = 20
+# offset = 0
+# tail = false
+# max_width = 0
+# precision =
+# renderer = ascii
+";
+
+/// Commands and mappings added to the vimrc, after `g:livid_control` is set to the
+/// path of the control FIFO. Other programs can send the same requests to the
+/// `control.sock` socket next to it.
+const VIMRC_COMMANDS: &str = r#"
+function! s:LividSend(request)
+    call writefile([a:request], g:livid_control, 'a')
+endfunction
+function! s:LividRenderers(...)
+    return ['ascii', 'unicode', 'markdown', 'csv', 'expanded']
+endfunction
+command! LividRerun call s:LividSend('rerun')
+command! -nargs=1 LividLimit call s:LividSend('limit ' . <q-args>)
+command! -nargs=1 LividOffset call s:LividSend('offset ' . <q-args>)
+command! -nargs=1 LividTail call s:LividSend('tail ' . <q-args>)
+command! -nargs=1 -complete=customlist,s:LividRenderers LividRenderer call s:LividSend('renderer ' . <q-args>)
+command! -nargs=+ LividSet call s:LividSend('set ' . <q-args>)
+command! -nargs=1 -complete=file LividExport call s:LividSend('export ' . fnamemodify(<q-args>, ':p'))
+command! -nargs=1 -complete=file LividInput call s:LividSend('input ' . fnamemodify(<q-args>, ':p'))
+nnoremap <Leader>lr :LividRerun<CR>
+nnoremap <Leader>ll :LividLimit<Space>
+nnoremap <Leader>lv :LividRenderer<Space>
+nnoremap <Leader>le :LividExport<Space>
+"#;
+
+const DIAGNOSTICS_KEPT: usize = 1000;
+const DIAGNOSTICS_SHOWN: usize = 10;
+
+/// Input problems collected over a single run
+#[derive(Debug, Default)]
+struct Diagnostics {
+    entries: Vec<Diagnostic>,
+    count: usize,
+    ragged_rows: usize,
+    parse_failures: Vec<usize>,
+    row_error: Option<CString>,
+}
+
+impl Diagnostics {
+    fn reset(&mut self, columns_count: usize) {
+        self.entries.clear();
+        self.count = 0;
+        self.ragged_rows = 0;
+        self.parse_failures = vec![0; columns_count];
+        self.row_error = None;
+    }
+
+    /// Add a problem with the row being read, which `api_last_error` reports
+    fn push(&mut self, diagnostic: Diagnostic) {
+        self.row_error = CString::new(diagnostic.to_string()).ok();
+        self.record(diagnostic);
+    }
+
+    fn record(&mut self, diagnostic: Diagnostic) {
+        self.count += 1;
+        if self.entries.len() < DIAGNOSTICS_KEPT {
+            self.entries.push(diagnostic);
+        }
+    }
+
+    fn report(&self, columns: &[Column]) {
+        if self.count == 0 {
+            return;
+        }
+        println!(
+            "Input diagnostics: {} problem(s), {} ragged row(s)",
+            self.count, self.ragged_rows
+        );
+        for diagnostic in self.entries.iter().take(DIAGNOSTICS_SHOWN) {
+            println!("  {}", diagnostic);
+        }
+        if self.count > DIAGNOSTICS_SHOWN {
+            println!("  ...and {} more", self.count - DIAGNOSTICS_SHOWN);
+        }
+        for (column, failures) in columns.iter().zip(self.parse_failures.iter()) {
+            if *failures > 0 {
+                println!(
+                    "Column {:?}: {} value(s) could not be parsed as {}",
+                    column.name, failures, column.type_str()
+                );
             }
-        } else {
-            self.empty_value()
         }
     }
 }
@@ -173,20 +546,61 @@ impl<'val> fmt::Debug for CStrPtr<'val> {
 }
 
 unsafe fn const_char_cstr<'a>(ptr: *const c_char) -> &'a CStr {
-    if ptr == std::ptr::null() {
+    if ptr.is_null() {
         Default::default()
     } else {
         CStr::from_ptr(ptr)
     }
 }
 
-impl<'val> CStrPtr<'val> {
-    fn to_string(&'val self) -> String {
-        unsafe { const_char_cstr(self.ptr) }
-            .to_str()
-            .unwrap()
-            .to_string()
+impl<'val> fmt::Display for CStrPtr<'val> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", escape_invalid_utf8(unsafe { const_char_cstr(self.ptr) }.to_bytes()))
+    }
+}
+
+/// Decode UTF-8, rendering any invalid bytes as `\xNN` escapes rather than failing
+fn escape_invalid_utf8(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                out.push_str(s);
+                return out;
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                out.push_str(std::str::from_utf8(valid).unwrap());
+                let invalid_len = e.error_len().unwrap_or(invalid.len());
+                for b in &invalid[..invalid_len] {
+                    out.push_str(&format!("\\x{:02x}", b));
+                }
+                rest = &invalid[invalid_len..];
+            }
+        }
+    }
+}
+
+/// Shorten `s` to at most `width` terminal columns, ending with an ellipsis if cut
+fn truncate_to_width(s: &str, width: usize) -> String {
+    if s.width() <= width {
+        return s.to_string();
     }
+    let mut out = String::new();
+    let mut used = 0;
+    for c in s.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + 1 > width {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    if width > 0 {
+        out.push('…');
+    }
+    out
 }
 
 impl<'col, 'val> fmt::Debug for Cell<'col, 'val> {
@@ -200,6 +614,8 @@ impl<'col, 'val> fmt::Debug for Cell<'col, 'val> {
                     CellType::Long => write!(f, "Long {:?}", self.value.long),
                     CellType::Time => write!(f, "Time {:?}", self.value.time),
                     CellType::Double => write!(f, "Double {:?}", self.value.double),
+                    CellType::Bool => write!(f, "Bool {:?}", self.value.boolean),
+                    CellType::Decimal => write!(f, "Decimal {}", format_decimal(self.value.decimal, self.column.scale)),
                 }
             }
         }
@@ -214,48 +630,519 @@ trait InputTable<'a> {
     fn reset(&'a mut self);
 }
 
+/// Where an output column's values come from
+#[derive(Debug, Clone, Copy)]
+enum InputField {
+    Column(usize),
+    /// `_source_file`: the path of the file the row was read from
+    SourceFile,
+    /// `_line`: the row's line number in that file
+    Line,
+}
+
+impl InputField {
+    fn find(columns: &[Column], name: &CStr) -> Option<InputField> {
+        match columns.iter().find(|c| c.name.as_c_str() == name) {
+            Some(column) => Some(InputField::Column(column.index)),
+            None if name.to_bytes() == b"_source_file" => Some(InputField::SourceFile),
+            None if name.to_bytes() == b"_line" => Some(InputField::Line),
+            None => None,
+        }
+    }
+}
+
+/// One of the files read into an input table, one after the other
 #[derive(Debug)]
-struct CsvInputFile {
+struct Source {
+    path: path::PathBuf,
+    /// `path`, for the `_source_file` column
+    name: CString,
+    compression: Compression,
+    /// Number of fields in the header
+    width: usize,
+    /// For each of the table's columns, the field of this file's rows that holds it
+    fields: Vec<Option<usize>>,
+}
+
+/// A row as read from one of the sources, before its fields are matched to columns
+struct RawRow {
+    source: usize,
+    line: usize,
+    /// `line`, for the `_line` column
+    line_text: CString,
+    fields: Vec<CString>,
+}
+
+impl RawRow {
+    fn field<'r>(&'r self, sources: &'r [Source], field: InputField) -> Option<&'r CString> {
+        match field {
+            InputField::Column(column) => {
+                let index = sources[self.source].fields.get(column).cloned()??;
+                self.fields.get(index)
+            }
+            InputField::SourceFile => Some(&sources[self.source].name),
+            InputField::Line => Some(&self.line_text),
+        }
+    }
+}
+
+/// Reads the rows of each source in turn
+struct SourceReader {
+    sources: Vec<Source>,
+    /// Index of the source being read
+    current: usize,
+    reader: Box<dyn BufRead>,
+    /// The file `reader` reads from, to tell whether it's been replaced
+    file: File,
+    line_buf: Vec<u8>,
+    line: usize,
     delimiter: char,
-    header: String,
-    line: String,
-    reader: io::BufReader<File>,
+    has_header: bool,
+    columns: usize,
+    /// Without a header, the first line of the first source is its first row
+    pending: Option<Vec<CString>>,
+    /// Keep reading rows appended to the last source after its end was reached
+    follow: bool,
+    /// Why the last source to end couldn't be read to its end, e.g. a truncated
+    /// compressed file
+    read_error: Option<Diagnostic>,
+}
+
+impl SourceReader {
+    fn read_row(&mut self) -> Option<RawRow> {
+        let fields = match self.pending.take() {
+            Some(fields) => fields,
+            None => loop {
+                let follow = self.follow && self.current + 1 == self.sources.len();
+                match read_fields(&mut self.reader, &mut self.line_buf, self.delimiter, self.columns, follow) {
+                    Ok(Some(fields)) => break fields,
+                    Ok(None) => {}
+                    Err(e) => {
+                        self.read_error = Some(Diagnostic {
+                            file: Some(self.sources[self.current].path.to_string_lossy().into_owned()),
+                            line: self.line + 1,
+                            column: None,
+                            raw: String::new(),
+                            reason: format!("unable to read the rest of the file: {}", e),
+                        })
+                    }
+                }
+                if self.current + 1 == self.sources.len() {
+                    return None;
+                }
+                self.current += 1;
+                if let Err(e) = self.open_current() {
+                    println!("Unable to read {:?}: {}", self.sources[self.current].path, e);
+                    return None;
+                }
+            },
+        };
+        self.line += 1;
+        Some(RawRow {
+            source: self.current,
+            line: self.line,
+            line_text: CString::new(self.line.to_string()).unwrap(),
+            fields,
+        })
+    }
+
+    /// Open the current source and skip its header
+    fn open_current(&mut self) -> Result<()> {
+        let path = &self.sources[self.current].path;
+        let file = File::open(path)?;
+        self.file = file.try_clone()?;
+        let (mut reader, _) = decompress(io::BufReader::new(file))?;
+        self.line = 0;
+        if self.has_header {
+            reader.read_until(b'\n', &mut vec![])?;
+            self.line = 1;
+        }
+        self.reader = reader;
+        self.line_buf.clear();
+        Ok(())
+    }
+
+    /// Start reading again from the first row of the first source
+    fn restart(&mut self) -> Result<()> {
+        self.current = 0;
+        self.pending = None;
+        self.read_error = None;
+        self.open_current()
+    }
+}
+
+/// A source opened by `open_source`, with its first line read
+struct OpenedSource {
+    reader: Box<dyn BufRead>,
+    file: File,
+    compression: Compression,
+    first_line: Vec<CString>,
+}
+
+/// Open `path`, decompressing it if it's compressed, and read its first line, split into
+/// at most `columns` fields (0 for no limit)
+fn open_source(path: &path::Path, delimiter: char, columns: usize, copy: Option<InputCopy>) -> Result<OpenedSource> {
+    let file = File::open(path)?;
+    let file_copy = file.try_clone()?;
+    let (mut reader, compression) = decompress(io::BufReader::new(TeeReader { inner: file, copy }))?;
+    let mut first_line = vec![];
+    reader.read_until(b'\n', &mut first_line)?;
+    let fields = split_fields(first_line.trim_ascii(), delimiter, columns)
+        .into_iter()
+        .map(|s| CString::new(s).unwrap())
+        .collect();
+    Ok(OpenedSource {
+        reader,
+        file: file_copy,
+        compression,
+        first_line: fields,
+    })
+}
+
+struct CsvInputFile {
+    reader: SourceReader,
+    /// Compressed files are decompressed again for each run rather than keeping every
+    /// row in `raw_cells`; set once rows have been dropped from it
+    rows_dropped: bool,
+    /// Whether rows can be dropped once they've been read, because the input can be read
+    /// again: it's compressed, and every file is a regular file rather than a pipe
+    can_reopen: bool,
     input_columns: Vec<Column>,
     output_columns: Vec<Column>,
-    output_input_map: Vec<Option<usize>>,
+    output_input_map: Vec<Option<InputField>>,
     row_index: usize,
-    raw_cells: Vec<Vec<CString>>,
+    raw_cells: Vec<RawRow>,
+    current_line: usize,
+    diagnostics: Diagnostics,
+    aborted: bool,
 }
 
 impl CsvInputFile {
-    fn new(input_path: &path::Path, delimiter: char) -> Result<Self> {
-        let input_file = File::open(&input_path)?;
-        let mut input_reader = io::BufReader::new(input_file);
-        let mut header = String::new();
-        input_reader.read_line(&mut header)?;
-
-        let columns = header
-            .trim()
-            .split(delimiter)
+    /// Read `paths` one after the other, copying everything read from the first to `copy`
+    /// if it's given. The columns are the union of the files' headers, in the order they
+    /// first appear; files without one of them read it as empty. Without a header line,
+    /// the columns are named `c1`, `c2`, ... and there are `columns` of them, or as many
+    /// as fields in the first row. Words in a row can't be told from fields when splitting
+    /// on whitespace, so then `columns` has to be given.
+    fn new(
+        paths: &[path::PathBuf],
+        delimiter: char,
+        has_header: bool,
+        columns: Option<usize>,
+        copy: Option<InputCopy>,
+    ) -> Result<Self> {
+        let width = match columns {
+            Some(columns) if !has_header => columns,
+            None if !has_header && delimiter == ' ' => {
+                return Err("--no-header with a space delimiter needs the number of columns, with --columns".into())
+            }
+            _ => 0,
+        };
+        let first = open_source(&paths[0], delimiter, width, copy)?;
+        let first_fields = first.first_line;
+        let is_file = |file: &File| file.metadata().map(|m| m.is_file()).unwrap_or(false);
+        let mut compressed = first.compression != Compression::None;
+        let mut regular = is_file(&first.file);
+        let mut headers = vec![(paths[0].clone(), first.compression, first_fields.clone())];
+        for path in &paths[1..] {
+            let source = open_source(path, delimiter, width, None)?;
+            compressed |= source.compression != Compression::None;
+            regular &= is_file(&source.file);
+            headers.push((path.clone(), source.compression, source.first_line));
+        }
+
+        let mut names: Vec<CString> = vec![];
+        if has_header {
+            for (path, _, fields) in headers.iter() {
+                if !names.is_empty() && *fields != names {
+                    println!("Header of {:?} differs from {:?}; missing columns are left empty", path, paths[0]);
+                }
+                for name in fields {
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            }
+        } else {
+            let count = columns.unwrap_or(first_fields.len());
+            names = (1..=count).map(|i| CString::new(format!("c{}", i)).unwrap()).collect();
+        }
+        let sources = headers
+            .into_iter()
+            .map(|(path, compression, fields)| Source {
+                name: CString::new(path.to_string_lossy().as_bytes()).unwrap(),
+                path,
+                compression,
+                width: if has_header { fields.len() } else { names.len() },
+                fields: if has_header {
+                    names.iter().map(|n| fields.iter().position(|f| f == n)).collect()
+                } else {
+                    (0..names.len()).map(Some).collect()
+                },
+            }).collect();
+
+        let columns: Vec<Column> = names
+            .into_iter()
             .enumerate()
-            .map(|(i, h)| Column {
-                name: CString::new(h).unwrap(),
+            .map(|(i, name)| Column {
+                name,
                 index: i,
                 cell_type: CellType::Text,
                 grid_width: 0,
+                on_error: OnError::Empty,
+                default_value: None,
+                scale: 0,
+                format: CellFormat::default(),
             }).collect();
 
-        return Ok(CsvInputFile {
-            delimiter: delimiter,
-            header: header.trim().to_string(),
-            line: String::new(),
-            reader: input_reader,
+        Ok(CsvInputFile {
+            reader: SourceReader {
+                sources,
+                current: 0,
+                reader: first.reader,
+                file: first.file,
+                line_buf: vec![],
+                line: if has_header { 1 } else { 0 },
+                delimiter,
+                has_header,
+                columns: columns.len(),
+                // The first line is a row like any other
+                pending: if has_header { None } else { Some(first_fields) },
+                follow: false,
+                read_error: None,
+            },
+            rows_dropped: false,
+            can_reopen: compressed && regular,
             input_columns: columns,
             output_input_map: vec![],
             output_columns: vec![],
             row_index: 0,
             raw_cells: vec![],
-        });
+            current_line: 0,
+            diagnostics: Diagnostics::default(),
+            aborted: false,
+        })
+    }
+
+    fn paths(&self) -> Vec<path::PathBuf> {
+        self.reader.sources.iter().map(|s| s.path.clone()).collect()
+    }
+
+    /// Guess the type of each input column from the first `sample_rows` rows.
+    /// The sampled rows are kept in `raw_cells`, so they're not lost from the input.
+    fn infer_types(&mut self, sample_rows: usize) {
+        while self.raw_cells.len() < sample_rows {
+            match self.reader.read_row() {
+                Some(row) => self.raw_cells.push(row),
+                None => break,
+            }
+        }
+        let sources = &self.reader.sources;
+        for column in self.input_columns.iter_mut() {
+            let values = self
+                .raw_cells
+                .iter()
+                .filter_map(|r| r.field(sources, InputField::Column(column.index)))
+                .map(|v| v.as_c_str());
+            let (cell_type, scale) = infer_type(values);
+            column.cell_type = cell_type;
+            column.scale = scale;
+        }
+    }
+
+    /// Whether the file has only been appended to since it was opened, so the rows
+    /// already read are still valid
+    fn only_appended(&mut self) -> bool {
+        let source = &self.reader.sources[0];
+        if self.reader.sources.len() > 1 || source.compression != Compression::None {
+            return false;
+        }
+        let file = &mut self.reader.file;
+        let (opened, current) = match (file.metadata(), fs::metadata(&source.path)) {
+            (Ok(opened), Ok(current)) => (opened, current),
+            _ => return false,
+        };
+        let position = match file.stream_position() {
+            Ok(position) => position,
+            Err(_) => return false,
+        };
+        opened.dev() == current.dev() && opened.ino() == current.ino() && current.len() >= position
+    }
+
+    fn report_diagnostics(&mut self) {
+        // Rows read in an earlier run may be kept, so this is reported every run
+        if let Some(ref error) = self.reader.read_error {
+            self.diagnostics.record(error.clone());
+        }
+        self.diagnostics.report(&self.output_columns);
+    }
+}
+
+/// Where input that can't be read again is saved. Only what's been read is copied, e.g.
+/// not the rest of a pipe after the script stopped early, so until the end of the input
+/// is reached a marker next to the copy says it's incomplete.
+#[derive(Debug)]
+struct InputCopy {
+    file: File,
+    partial: path::PathBuf,
+}
+
+impl InputCopy {
+    fn create(path: &path::Path) -> io::Result<InputCopy> {
+        let partial = partial_marker(path);
+        fs::write(&partial, "")?;
+        Ok(InputCopy {
+            file: File::create(path)?,
+            partial,
+        })
+    }
+}
+
+/// The file that's there while the copy of the input at `path` is incomplete
+fn partial_marker(path: &path::Path) -> path::PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".partial");
+    marker.into()
+}
+
+/// Copies everything read from `inner` to `copy`, so input from a pipe can be read again
+/// after a restart. If the copy can't be written, e.g. the disk is full, it's reported
+/// and copying stops, but `inner` is still read to the end.
+#[derive(Debug)]
+struct TeeReader<R> {
+    inner: R,
+    copy: Option<InputCopy>,
+}
+
+impl<R: io::Read> io::Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref mut copy) = self.copy {
+            if n == 0 && !buf.is_empty() {
+                // Everything has been copied
+                let _ = fs::remove_file(&copy.partial);
+                self.copy = None;
+            } else if let Err(e) = copy.file.write_all(&buf[..n]) {
+                println!("Unable to copy the input, later runs will only read what was copied: {}", e);
+                self.copy = None;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Read a line and split it into fields. Lines are read as bytes, so input that isn't
+/// valid UTF-8 is passed through rather than ending the input. When following a file
+/// that's being appended to, an unfinished last line is kept in `line_buf` until the
+/// rest of it arrives. `None` at the end of the input.
+fn read_fields<R: BufRead>(
+    reader: &mut R,
+    line_buf: &mut Vec<u8>,
+    delimiter: char,
+    columns: usize,
+    follow: bool,
+) -> io::Result<Option<Vec<CString>>> {
+    if !follow || line_buf.ends_with(b"\n") {
+        line_buf.clear();
+    }
+    Ok(match reader.read_until(b'\n', line_buf)? {
+        0 => None,
+        _ if follow && !line_buf.ends_with(b"\n") => None,
+        _ => Some(
+            split_fields(line_buf.trim_ascii(), delimiter, columns)
+                .into_iter()
+                .map(|s| CString::new(s).unwrap())
+                .collect(),
+        ),
+    })
+}
+
+/// Split `line` on `delimiter`. A space delimiter splits on runs of whitespace, like
+/// `awk`, and the last of `columns` fields takes the rest of the line, so the output of
+/// commands like `ps` keeps its last column whole (0 for no limit).
+fn split_fields(line: &[u8], delimiter: char, columns: usize) -> Vec<&[u8]> {
+    if delimiter == ' ' {
+        let mut fields = vec![];
+        let mut rest = line;
+        while !rest.is_empty() {
+            if fields.len() + 1 == columns {
+                fields.push(rest);
+                break;
+            }
+            let end = rest.iter().position(u8::is_ascii_whitespace).unwrap_or(rest.len());
+            fields.push(&rest[..end]);
+            rest = rest[end..].trim_ascii_start();
+        }
+        return fields;
+    }
+    let mut delimiter_buf = [0; 4];
+    split_on(line, delimiter.encode_utf8(&mut delimiter_buf).as_bytes())
+}
+
+/// Split `text` on each occurrence of `separator`, which mustn't be empty
+fn split_on<'t>(text: &'t [u8], separator: &[u8]) -> Vec<&'t [u8]> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i + separator.len() <= text.len() {
+        if &text[i..i + separator.len()] == separator {
+            parts.push(&text[start..i]);
+            i += separator.len();
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Largest number of fraction digits for a column to be inferred as `DECIMAL`
+const INFER_MAX_SCALE: usize = 4;
+
+/// Guess a column type from sample values. Empty values are ignored, so a column of
+/// integers with some blanks is still a `LONG` (with the blanks as empty cells).
+fn infer_type<'a, I: Iterator<Item = &'a CStr>>(values: I) -> (CellType, i16) {
+    let mut seen = false;
+    let (mut long, mut boolean, mut decimal, mut double) = (true, true, true, true);
+    let mut scale = None;
+    for value in values {
+        let s = match value.to_str() {
+            Ok("") => continue,
+            Ok(s) => s,
+            Err(_) => return (CellType::Text, 0),
+        };
+        seen = true;
+        long &= i64::from_str(s).is_ok();
+        boolean &= parse_bool(s).is_some();
+        double &= f64::from_str(s).is_ok();
+        if decimal {
+            // All values with a fraction must have the same number of digits, e.g. "12.50"
+            let digits = s.find('.').map(|i| s.len() - i - 1);
+            decimal = match digits {
+                Some(d) => {
+                    d > 0
+                        && d <= INFER_MAX_SCALE
+                        && scale.is_none_or(|x| x == d)
+                        && parse_decimal(s, d as i16).is_some()
+                }
+                None => parse_decimal(s, 0).is_some(),
+            };
+            scale = digits.or(scale);
+        }
+    }
+    if !seen {
+        (CellType::Text, 0)
+    } else if long {
+        (CellType::Long, 0)
+    } else if boolean {
+        (CellType::Bool, 0)
+    } else if let (true, Some(scale)) = (decimal, scale) {
+        (CellType::Decimal, scale as i16)
+    } else if double {
+        (CellType::Double, 0)
+    } else {
+        (CellType::Text, 0)
     }
 }
 
@@ -273,56 +1160,104 @@ impl<'a> InputTable<'a> for CsvInputFile {
         self.output_input_map = self
             .output_columns
             .iter()
-            .map(|oc| {
-                self.input_columns
-                    .iter()
-                    .find(|ic| ic.name == oc.name)
-                    .map(|ic| ic.index)
-            }).collect();
+            .map(|oc| InputField::find(&self.input_columns, &oc.name))
+            .collect();
+        self.diagnostics.reset(self.output_columns.len());
     }
 
     fn next(&'a mut self) -> Option<Vec<Cell<'a, 'a>>> {
+        let retain = !self.can_reopen;
         let raw_cells = &mut self.raw_cells;
-        let line_buf = &mut self.line;
-        let delimiter = self.delimiter;
+        let rows_dropped = &mut self.rows_dropped;
         let reader = &mut self.reader;
-        let input_len = self.input_columns.len();
         let output_input_map: &Vec<_> = &self.output_input_map;
         let output_columns = &self.output_columns;
+        let diagnostics = &mut self.diagnostics;
+        diagnostics.row_error = None;
+        if self.aborted {
+            return None;
+        }
         let raw_row = if raw_cells.len() > self.row_index {
             raw_cells.get(self.row_index)
         } else {
-            line_buf.clear();
-            reader.read_line(line_buf).ok().and_then(move |rc| {
-                if rc <= 0 {
-                    return None;
+            reader.read_row().map(move |row| {
+                // The previous row is kept, as scripts may still be using its text
+                if !retain && raw_cells.len() > 1 {
+                    raw_cells.drain(..raw_cells.len() - 1);
+                    *rows_dropped = true;
                 }
-                let mut l: Vec<CString> = line_buf
-                    .trim()
-                    .split(delimiter)
-                    .map(|s| CString::new(s).unwrap())
-                    .collect();
-                l.resize(input_len, CString::default());
-                raw_cells.push(l);
-                raw_cells.last()
+                raw_cells.push(row);
+                raw_cells.last().unwrap()
             })
         }?;
+        let sources = &reader.sources;
+        let source = &sources[raw_row.source];
+        // Name the file in diagnostics when there's more than one
+        let file = if sources.len() > 1 { Some(source.path.to_string_lossy().into_owned()) } else { None };
+        let line = raw_row.line;
         self.row_index += 1;
-        Some(
-            output_input_map
+        self.current_line = line;
+        let width = source.width;
+        if raw_row.fields.len() != width {
+            let reason = if raw_row.fields.len() < width {
+                format!("short row: {} of {} fields, padded with empty cells", raw_row.fields.len(), width)
+            } else {
+                format!("long row: {} of {} fields, extra fields dropped", raw_row.fields.len(), width)
+            };
+            let raw = raw_row
+                .fields
                 .iter()
-                .zip(output_columns.iter())
-                .map(|(opt_idx, col)| {
-                    opt_idx
-                        .and_then(|x| raw_row.get(x))
-                        .map(|x| col.parse_value(x))
-                        .unwrap_or(col.empty_value())
-                }).collect(),
-        )
+                .map(|c| c.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(&reader.delimiter.to_string());
+            diagnostics.ragged_rows += 1;
+            diagnostics.push(Diagnostic {
+                file: file.clone(),
+                line,
+                column: None,
+                raw,
+                reason,
+            });
+        }
+        let row: Option<Vec<_>> = output_input_map
+            .iter()
+            .zip(output_columns.iter())
+            .map(|(field, col)| {
+                match field.and_then(|f| raw_row.field(sources, f)) {
+                    Some(raw) => col.parse_value(raw).or_else(|| {
+                        let diagnostic = Diagnostic {
+                            file: file.clone(),
+                            line,
+                            column: Some(col.name.clone()),
+                            raw: raw.to_string_lossy().into_owned(),
+                            reason: format!("not a valid {}", col.type_str()),
+                        };
+                        let cell = col.parse_error(&diagnostic);
+                        diagnostics.parse_failures[col.index] += 1;
+                        diagnostics.push(diagnostic);
+                        cell
+                    }),
+                    None => Some(col.empty_value()),
+                }
+            }).collect();
+        self.aborted = row.is_none();
+        row
     }
 
     fn reset(&'a mut self) {
+        if self.rows_dropped {
+            self.raw_cells.clear();
+            self.rows_dropped = false;
+            if let Err(e) = self.reader.restart() {
+                println!("Unable to read {:?} again: {}", self.reader.sources[0].path, e);
+                self.aborted = true;
+                return;
+            }
+        }
         self.row_index = 0;
+        self.current_line = 0;
+        self.aborted = false;
+        self.diagnostics.reset(self.output_columns.len());
     }
 }
 
@@ -330,20 +1265,164 @@ impl<'a> InputTable<'a> for CsvInputFile {
 struct LividApi<'a> {
     next: extern "C" fn(api: *mut LividApi<'a>, row_out: *mut CellValue<'a>, empty_out: *mut i8) -> i8,
     grid: extern "C" fn(api: *mut LividApi<'a>, row: *const CellValue<'a>, empty: *const i8) -> i8,
-    write: extern "C" fn(api: *mut LividApi<'a>, string: *const c_char) -> (),
+    write: extern "C" fn(api: *mut LividApi<'a>, string: *const c_char),
+    row_number: extern "C" fn(api: *mut LividApi<'a>) -> i64,
+    last_error: extern "C" fn(api: *mut LividApi<'a>) -> *const c_char,
+    arena_alloc: extern "C" fn(api: *mut LividApi<'a>, size: usize) -> *mut u8,
+    arena_strdup: extern "C" fn(api: *mut LividApi<'a>, string: *const c_char) -> *const c_char,
+    set: extern "C" fn(api: *mut LividApi<'a>, key: *const c_char, value: *const c_char) -> i8,
+    lookup: extern "C" fn(
+        api: *mut LividApi<'a>,
+        name: *const c_char,
+        columns: *const CColumn,
+        columns_count: usize,
+        key: *const c_char,
+        row_out: *mut CellValue<'a>,
+        empty_out: *mut i8,
+    ) -> i8,
+    regex_match: extern "C" fn(api: *mut LividApi<'a>, pattern: *const c_char, text: *const c_char) -> i8,
+    regex_capture:
+        extern "C" fn(api: *mut LividApi<'a>, pattern: *const c_char, text: *const c_char, group: usize) -> *const c_char,
+    regex_replace: extern "C" fn(
+        api: *mut LividApi<'a>,
+        pattern: *const c_char,
+        text: *const c_char,
+        replacement: *const c_char,
+    ) -> *const c_char,
+    split: extern "C" fn(
+        api: *mut LividApi<'a>,
+        text: *const c_char,
+        separator: *const c_char,
+        count_out: *mut usize,
+    ) -> *const *const c_char,
+    convert: extern "C" fn(api: *mut LividApi<'a>, text: *const c_char, conversion: i8) -> *const c_char,
+    map_new: extern "C" fn(api: *mut LividApi<'a>) -> *mut Map,
+    map_update: extern "C" fn(
+        api: *mut LividApi<'a>,
+        map: *mut Map,
+        text_key: bool,
+        text: *const c_char,
+        number: i64,
+        op: i8,
+        value: i64,
+        value_out: *mut i64,
+    ) -> i8,
+    map_len: extern "C" fn(api: *mut LividApi<'a>, map: *const Map) -> usize,
+    map_entry: extern "C" fn(
+        api: *mut LividApi<'a>,
+        map: *const Map,
+        index: usize,
+        text_out: *mut *const c_char,
+        number_out: *mut i64,
+        value_out: *mut i64,
+    ) -> i8,
+    digest_new: extern "C" fn(api: *mut LividApi<'a>) -> *mut Digest,
+    digest_add: extern "C" fn(api: *mut LividApi<'a>, digest: *mut Digest, value: f64),
+    digest_quantile: extern "C" fn(api: *mut LividApi<'a>, digest: *mut Digest, q: f64) -> f64,
+    digest_count: extern "C" fn(api: *mut LividApi<'a>, digest: *const Digest) -> u64,
+    distinct_new: extern "C" fn(api: *mut LividApi<'a>) -> *mut Distinct,
+    distinct_add: extern "C" fn(
+        api: *mut LividApi<'a>,
+        distinct: *mut Distinct,
+        text_key: bool,
+        text: *const c_char,
+        number: i64,
+    ),
+    distinct_count: extern "C" fn(api: *mut LividApi<'a>, distinct: *const Distinct) -> i64,
+    history: extern "C" fn(api: *mut LividApi<'a>, rows: usize),
+    lag: extern "C" fn(api: *mut LividApi<'a>, k: usize, row_out: *mut CellValue<'a>, empty_out: *mut i8) -> i8,
+    lead: extern "C" fn(api: *mut LividApi<'a>, k: usize, row_out: *mut CellValue<'a>, empty_out: *mut i8) -> i8,
+    partition_new: extern "C" fn(api: *mut LividApi<'a>) -> *mut Partition,
+    partition_state: extern "C" fn(
+        api: *mut LividApi<'a>,
+        partition: *mut Partition,
+        text_key: bool,
+        text: *const c_char,
+        number: i64,
+        size: usize,
+    ) -> *mut u8,
+    partition_entry: extern "C" fn(
+        api: *mut LividApi<'a>,
+        partition: *const Partition,
+        index: usize,
+        text_out: *mut *const c_char,
+        number_out: *mut i64,
+    ) -> *mut u8,
     input: &'a mut CsvInputFile,
     editor: &'a mut Editor,
+    lookups: &'a [Lookup],
+    /// Lookup structs the script has used this run, by the address of their column list
+    lookup_bindings: HashMap<*const CColumn, Option<LookupBinding>>,
+    regexes: RegexCache,
+    /// Maps and sets created by the script; boxed, so the handles it holds stay valid
+    #[allow(clippy::vec_box)]
+    maps: Vec<Box<Map>>,
+    #[allow(clippy::vec_box)]
+    digests: Vec<Box<Digest>>,
+    #[allow(clippy::vec_box)]
+    distincts: Vec<Box<Distinct>>,
+    /// Rows kept for `lag` and read ahead by `lead`
+    window: Window,
+    #[allow(clippy::vec_box)]
+    partitions: Vec<Box<Partition>>,
+    arena: Arena,
+}
+
+#[repr(C, align(16))]
+#[derive(Copy, Clone)]
+struct ArenaChunk([u8; 16]);
+
+/// Memory handed out to a script during a single run; it's all freed when the run's
+/// `LividApi` is dropped, after `run` returns.
+#[derive(Default)]
+struct Arena {
+    blocks: Vec<Box<[ArenaChunk]>>,
+}
+
+impl Arena {
+    /// Allocate zeroed memory, aligned like `malloc`
+    fn alloc(&mut self, size: usize) -> *mut u8 {
+        let chunks = size.div_ceil(16).max(1);
+        let mut block = vec![ArenaChunk([0; 16]); chunks].into_boxed_slice();
+        let ptr = block.as_mut_ptr() as *mut u8;
+        self.blocks.push(block);
+        ptr
+    }
+
+    fn strdup(&mut self, string: &CStr) -> *const c_char {
+        self.copy(string.to_bytes())
+    }
+
+    /// Copy `bytes` into a NUL-terminated string
+    fn copy(&mut self, bytes: &[u8]) -> *const c_char {
+        // The allocation is zeroed, so the NUL is already there
+        let ptr = self.alloc(bytes.len() + 1);
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+        ptr as *const c_char
+    }
 }
 
 extern "C" fn livid_api_raw_next<'a>(api: *mut LividApi<'a>, row_out: *mut CellValue<'a>, empty_out: *mut i8) -> i8 {
     unsafe {
-        if let Some(row) = (*api).input.next() {
+        let api = &mut (*api);
+        if api.window.active() {
+            return match api.window.next(api.input) {
+                Some(row) => {
+                    row.write(row_out, empty_out);
+                    1
+                }
+                None => 0,
+            };
+        }
+        if let Some(row) = api.input.next() {
+            api.window.returned(&row);
             for (i, cell) in row.iter().enumerate() {
-                row_out.add(i).write(cell.value.clone());
+                row_out.add(i).write(cell.value);
                 empty_out.add(i).write(cell.empty as i8);
             }
             1
         } else {
+            api.window.returned(&[]);
             0
         }
     }
@@ -362,23 +1441,134 @@ extern "C" fn livid_api_raw_grid<'a>(api: *mut LividApi<'a>, row: *const CellVal
     }
 }
 
-extern "C" fn livid_api_raw_write<'a>(api: *mut LividApi<'a>, string: *const i8) -> () {
+extern "C" fn livid_api_raw_write<'a>(api: *mut LividApi<'a>, string: *const i8) {
     unsafe {
         (*api)
             .editor
-            .write(const_char_cstr(string).to_str().unwrap())
+            .write(&escape_invalid_utf8(const_char_cstr(string).to_bytes()))
             .unwrap()
     }
 }
 
-impl<'a> LividApi<'a> {
-    fn new(input: &'a mut CsvInputFile, editor: &'a mut Editor) -> Self {
-        LividApi {
-            next: livid_api_raw_next,
-            grid: livid_api_raw_grid,
-            write: livid_api_raw_write,
-            input: input,
-            editor: editor,
+extern "C" fn livid_api_raw_row_number<'a>(api: *mut LividApi<'a>) -> i64 {
+    unsafe {
+        // The input may have been read ahead of the current row
+        match (*api).window.current() {
+            Some(row) => row.line() as i64,
+            None => (*api).input.current_line as i64,
+        }
+    }
+}
+
+extern "C" fn livid_api_raw_last_error<'a>(api: *mut LividApi<'a>) -> *const c_char {
+    unsafe {
+        let api = &*api;
+        let error = match api.window.current() {
+            Some(row) => row.error(),
+            None => api.input.diagnostics.row_error.as_ref(),
+        };
+        error.map(|e| e.as_ptr()).unwrap_or(std::ptr::null())
+    }
+}
+
+extern "C" fn livid_api_raw_arena_alloc<'a>(api: *mut LividApi<'a>, size: usize) -> *mut u8 {
+    unsafe { (*api).arena.alloc(size) }
+}
+
+extern "C" fn livid_api_raw_arena_strdup<'a>(api: *mut LividApi<'a>, string: *const c_char) -> *const c_char {
+    unsafe { (*api).arena.strdup(const_char_cstr(string)) }
+}
+
+extern "C" fn livid_api_raw_set<'a>(api: *mut LividApi<'a>, key: *const c_char, value: *const c_char) -> i8 {
+    unsafe {
+        let key = escape_invalid_utf8(const_char_cstr(key).to_bytes());
+        let value = escape_invalid_utf8(const_char_cstr(value).to_bytes());
+        (*api).editor.script_set(&key, &value) as i8
+    }
+}
+
+extern "C" fn livid_api_raw_lookup<'a>(
+    api: *mut LividApi<'a>,
+    name: *const c_char,
+    columns: *const CColumn,
+    columns_count: usize,
+    key: *const c_char,
+    row_out: *mut CellValue<'a>,
+    empty_out: *mut i8,
+) -> i8 {
+    unsafe {
+        let api = &mut (*api);
+        let lookups = api.lookups;
+        let binding = api.lookup_bindings.entry(columns).or_insert_with(|| {
+            let name = const_char_cstr(name).to_string_lossy();
+            LookupBinding::new(lookups, &name, slice::from_raw_parts(columns, columns_count))
+        });
+        let binding = match *binding {
+            Some(ref binding) => binding,
+            None => return 0,
+        };
+        let record = match lookups[binding.lookup].get(const_char_cstr(key)) {
+            Some(record) => record,
+            None => return 0,
+        };
+        for (i, (column, field)) in binding.columns.iter().enumerate() {
+            // Values that don't parse are left empty, like missing columns
+            let cell = field
+                .and_then(|f| record.get(f))
+                .and_then(|raw| column.parse_value(raw))
+                .unwrap_or_else(|| column.empty_value());
+            row_out.add(i).write(cell.value);
+            empty_out.add(i).write(cell.empty as i8);
+        }
+        1
+    }
+}
+
+impl<'a> LividApi<'a> {
+    fn new(input: &'a mut CsvInputFile, editor: &'a mut Editor, lookups: &'a [Lookup]) -> Self {
+        LividApi {
+            next: livid_api_raw_next,
+            grid: livid_api_raw_grid,
+            write: livid_api_raw_write,
+            row_number: livid_api_raw_row_number,
+            last_error: livid_api_raw_last_error,
+            arena_alloc: livid_api_raw_arena_alloc,
+            arena_strdup: livid_api_raw_arena_strdup,
+            set: livid_api_raw_set,
+            lookup: livid_api_raw_lookup,
+            regex_match: text::livid_api_raw_regex_match,
+            regex_capture: text::livid_api_raw_regex_capture,
+            regex_replace: text::livid_api_raw_regex_replace,
+            split: text::livid_api_raw_split,
+            convert: text::livid_api_raw_convert,
+            map_new: maps::livid_api_raw_map_new,
+            map_update: maps::livid_api_raw_map_update,
+            map_len: maps::livid_api_raw_map_len,
+            map_entry: maps::livid_api_raw_map_entry,
+            digest_new: sketches::livid_api_raw_digest_new,
+            digest_add: sketches::livid_api_raw_digest_add,
+            digest_quantile: sketches::livid_api_raw_digest_quantile,
+            digest_count: sketches::livid_api_raw_digest_count,
+            distinct_new: sketches::livid_api_raw_distinct_new,
+            distinct_add: sketches::livid_api_raw_distinct_add,
+            distinct_count: sketches::livid_api_raw_distinct_count,
+            history: window::livid_api_raw_history,
+            lag: window::livid_api_raw_lag,
+            lead: window::livid_api_raw_lead,
+            partition_new: window::livid_api_raw_partition_new,
+            partition_state: window::livid_api_raw_partition_state,
+            partition_entry: window::livid_api_raw_partition_entry,
+            input,
+            editor,
+            lookups,
+            lookup_bindings: HashMap::new(),
+            regexes: RegexCache::default(),
+            maps: vec![],
+            digests: vec![],
+            distincts: vec![],
+            window: Window::default(),
+            partitions: vec![],
+            arena: Arena::default(),
         }
     }
 }
@@ -388,7 +1578,7 @@ struct LividLib<'a> {
     columns: *const CColumn,
     columns_count: &'a usize,
     grid_rows_limit: &'a usize,
-    run: extern "C" fn(api: &'a LividApi<'a>) -> (),
+    run: extern "C" fn(api: *mut LividApi<'a>),
 }
 
 struct StdioRedirector {
@@ -405,8 +1595,8 @@ impl StdioRedirector {
             libc::close(2);
             libc::dup(target_fd);
             StdioRedirector {
-                stdout_fd: stdout_fd,
-                stderr_fd: stderr_fd,
+                stdout_fd,
+                stderr_fd,
             }
         }
     }
@@ -426,31 +1616,77 @@ impl Drop for StdioRedirector {
 
 struct Editor {
     workspace: path::PathBuf,
+    /// Name of the vim server started by `launch`
+    servername: String,
+    /// Servers of vims started by `livid attach`
+    attached: Vec<String>,
     vimrc_path: path::PathBuf,
     script_file: File,
     log_file: File,
     output_file: File,
-    script_notify: inotify::Inotify,
-    grid_rows: usize,
-    grid_rows_limit: usize,
-    auto_widths: Vec<usize>,
+    syntax_path: path::PathBuf,
+    /// Contents of the syntax file, to avoid rewriting it when nothing changed
+    syntax: String,
+    settings_path: path::PathBuf,
+    notify: inotify::Inotify,
+    script_watch: inotify::WatchDescriptor,
+    settings_watch: inotify::WatchDescriptor,
+    /// Watches on the directories of the input files, and the files' names
+    input_watches: Vec<(inotify::WatchDescriptor, std::ffi::OsString)>,
+    output: OutputBuffer,
+    /// Settings from the command line, used at the start of each run
+    settings: ViewSettings,
+    /// Settings from the workspace's `settings` file and from requests, which take
+    /// precedence over the script's
+    overrides: Vec<(String, String)>,
+    /// Settings changed by requests, which last for the rest of the session
+    session_overrides: Vec<(String, String)>,
+    /// FIFO that vim writes requests to
+    control: LineReader<File>,
+    /// Socket for requests from other programs, which are answered with JSON
+    socket: UnixListener,
+    clients: Vec<LineReader<UnixStream>>,
+    /// Clients waiting for the status at the end of the next run
+    waiting: Vec<UnixStream>,
+    /// Read end of the pipe that SIGINT and SIGTERM are reported on
+    signals: File,
+    vim_pid: Option<u32>,
+    /// Hung up when vim exits
+    vim_exited: Option<UnixStream>,
+    /// Where to write every row of the next run, instead of previewing it
+    export: Option<path::PathBuf>,
+    /// Why the last export failed, for the clients waiting on it
+    export_failure: Option<String>,
+    /// Responses to requests, shown in the log of the next run
+    acknowledgements: Vec<String>,
     redirector: StdioRedirector,
     last_reload: Instant,
 }
 
 impl Editor {
-    fn new() -> Result<Self> {
-        let workspace = path::PathBuf::from("./wkspace");
-        fs::create_dir_all(&workspace)?;
+    /// Set up the session's workspace. When resuming, the script and settings files
+    /// already there are kept.
+    fn new(session: &Session, resume: bool) -> Result<Self> {
+        let workspace = session.workspace.clone();
 
         let header_file_path = workspace.join("livid.h");
         let mut header_file = File::create(&header_file_path)?;
         header_file.write_all(include_str!("../c_src/livid.h").as_bytes())?;
 
         let script_file_path = workspace.join("script.c");
-        let script_file = File::create(&script_file_path)?;
-        let mut script_notify = inotify::Inotify::init()?;
-        script_notify.add_watch(script_file_path.clone(), inotify::WatchMask::CLOSE_WRITE)?;
+        let script_file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(!resume)
+            .open(&script_file_path)?;
+        let mut notify = inotify::Inotify::init()?;
+        let script_watch = notify.add_watch(script_file_path.clone(), inotify::WatchMask::CLOSE_WRITE)?;
+
+        let settings_path = workspace.join("settings");
+        if !resume || !settings_path.exists() {
+            fs::write(&settings_path, SETTINGS_TEMPLATE)?;
+        }
+        let settings_watch = notify.add_watch(settings_path.clone(), inotify::WatchMask::CLOSE_WRITE)?;
 
         let log_file_path = workspace.join("log");
         let log_file = File::create(&log_file_path)?;
@@ -458,64 +1694,132 @@ impl Editor {
         let output_file_path = workspace.join("output");
         let output_file = File::create(&output_file_path)?;
 
+        let control_path = workspace.join("control");
+        if control_path.exists() {
+            fs::remove_file(&control_path)?;
+        }
+        nix::unistd::mkfifo(&control_path, nix::sys::stat::Mode::S_IRUSR | nix::sys::stat::Mode::S_IWUSR)?;
+        // Opened for writing too, so reads don't see end-of-file between requests
+        let control = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&control_path)?;
+
+        let socket_path = workspace.join("control.sock");
+        if socket_path.exists() {
+            fs::remove_file(&socket_path)?;
+        }
+        let socket = UnixListener::bind(&socket_path)?;
+        socket.set_nonblocking(true)?;
+
+        let syntax_path = workspace.join("output.vim");
+        let syntax = OutputBuffer::new().syntax();
+        fs::write(&syntax_path, &syntax)?;
+
         let vimrc_path = workspace.join("vimrc");
         {
             let mut vimrc = File::create(&vimrc_path)?;
-            write!(vimrc, "set backupcopy=yes\n")?;
-            write!(vimrc, "set autoread\n")?;
-            write!(vimrc, "set splitbelow\n")?;
-            write!(vimrc, "edit {}\n", output_file_path.to_str().unwrap())?;
-            write!(vimrc, "split {}\n", log_file_path.to_str().unwrap())?;
-            write!(vimrc, "vsplit {}\n", script_file_path.to_str().unwrap())?;
+            writeln!(vimrc, "set backupcopy=yes")?;
+            // The syntax file is regenerated as the grid's columns change; re-read it
+            // whenever the output is reloaded
+            writeln!(
+                vimrc,
+                "autocmd BufReadPost {} source {}",
+                fs::canonicalize(&output_file_path)?.to_str().unwrap(),
+                fs::canonicalize(&syntax_path)?.to_str().unwrap()
+            )?;
+            writeln!(vimrc, "let g:livid_control = '{}'", fs::canonicalize(&control_path)?.to_str().unwrap())?;
+            vimrc.write_all(VIMRC_COMMANDS.as_bytes())?;
+            writeln!(vimrc, "set autoread")?;
+            writeln!(vimrc, "set splitbelow")?;
+            writeln!(vimrc, "edit {}", output_file_path.to_str().unwrap())?;
+            writeln!(vimrc, "split {}", log_file_path.to_str().unwrap())?;
+            writeln!(vimrc, "vsplit {}", script_file_path.to_str().unwrap())?;
         }
 
         let log_fd = log_file.as_raw_fd();
 
         Ok(Editor {
-            workspace: workspace,
-            vimrc_path: vimrc_path,
-            script_file: script_file,
-            script_notify: script_notify,
-            log_file: log_file,
-            output_file: output_file,
-            grid_rows: 0,
-            grid_rows_limit: 20,
-            auto_widths: vec![],
+            workspace,
+            servername: session.servername.clone(),
+            attached: vec![],
+            vimrc_path,
+            script_file,
+            log_file,
+            output_file,
+            syntax_path,
+            syntax,
+            settings_path,
+            notify,
+            script_watch,
+            settings_watch,
+            input_watches: vec![],
+            output: OutputBuffer::new(),
+            settings: ViewSettings::default(),
+            overrides: vec![],
+            session_overrides: vec![],
+            control: LineReader::new(control),
+            socket,
+            clients: vec![],
+            waiting: vec![],
+            signals: catch_signals()?,
+            vim_pid: None,
+            vim_exited: None,
+            export: None,
+            export_failure: None,
+            acknowledgements: vec![],
             redirector: StdioRedirector::new(log_fd),
             last_reload: Instant::now(),
         })
     }
 
-    fn launch(&mut self) -> Result<thread::JoinHandle<()>> {
+    fn launch(&mut self) -> Result<()> {
         let vim_stdin = File::open("/dev/tty")?;
         let vim_stdout = File::create("/dev/tty")?;
         let vim_stderr = self.log_file.try_clone()?;
-        let vimrc_path = self.vimrc_path.clone();
-        Ok(thread::spawn(move || {
-            Command::new("vim")
-                .arg("--servername")
-                .arg("livid")
-                .arg("-S")
-                .arg(vimrc_path.as_os_str())
-                .stdin(Stdio::from(vim_stdin))
-                .stdout(Stdio::from(vim_stdout))
-                .stderr(Stdio::from(vim_stderr))
-                .status()
-                .unwrap();
-        }))
+        let mut vim = Command::new("vim")
+            .arg("--servername")
+            .arg(&self.servername)
+            .arg("-S")
+            .arg(self.vimrc_path.as_os_str())
+            .stdin(Stdio::from(vim_stdin))
+            .stdout(Stdio::from(vim_stdout))
+            .stderr(Stdio::from(vim_stderr))
+            .spawn()?;
+        let (vim_exited, hang_up) = UnixStream::pair()?;
+        vim_exited.set_nonblocking(true)?;
+        self.vim_pid = Some(vim.id());
+        self.vim_exited = Some(vim_exited);
+        thread::spawn(move || {
+            let _ = vim.wait();
+            drop(hang_up);
+        });
+        Ok(())
+    }
+
+    fn vim_running(&mut self) -> bool {
+        let mut byte = [0];
+        match self.vim_exited {
+            Some(ref mut stream) => {
+                matches!(stream.read(&mut byte), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+            }
+            None => false,
+        }
     }
 
     fn reload(&mut self, force: bool) -> Result<()> {
+        if self.export.is_some() && !force {
+            return Ok(());
+        }
         let now = Instant::now();
         if force || now > self.last_reload + Duration::from_millis(100) {
             self.last_reload = now;
             self.output_file.sync_all()?;
-            Command::new("vim")
-                .arg("--servername")
-                .arg("livid")
-                .arg("--remote-send")
-                .arg("<Esc>:checktime<CR>")
-                .status()?;
+            remote_send(&self.servername, "<Esc>:checktime<CR>")?;
+            for servername in self.attached.iter() {
+                remote_send(servername, "<Esc>:checktime<CR>")?;
+            }
         }
         Ok(())
     }
@@ -541,180 +1845,1004 @@ impl Editor {
         Ok(lib_path)
     }
 
-    fn grid<'a>(&mut self, columns: &Vec<Column>, values: &[CellValue<'a>], emptys: &[i8]) -> Result<bool> {
+    fn grid<'a>(&mut self, columns: &[Column], values: &[CellValue<'a>], emptys: &[i8]) -> Result<bool> {
         assert!(columns.len() == values.len());
         assert!(columns.len() == emptys.len());
 
-        if self.auto_widths.len() < columns.len() {
-            self.auto_widths.resize(columns.len(), 0);
-        }
-        if self.grid_rows == 0 {
-            for (column, auto_width) in columns.iter()
-                .zip(self.auto_widths.iter_mut()) {
-                let grid_width = column.grid_width;
-                let width = if grid_width < 0 {
-                    continue;
-                } else if grid_width == 0 {
-                    *auto_width
-                } else {
-                    grid_width as usize
-                };
-                let string_value = column.name.to_str().unwrap();
-                write!(
-                    self.output_file,
-                    "| {val:>width$} ",
-                    width = width,
-                    val = string_value
-                )?;
-                *auto_width = std::cmp::max(*auto_width, string_value.len());
-            }
-            write!(self.output_file, "|\n")?;
-
-            for (column, auto_width) in columns.iter()
-                .zip(self.auto_widths.iter_mut()) {
-                let grid_width = column.grid_width;
-                let width = if grid_width < 0 {
-                    continue;
-                } else if grid_width == 0 {
-                    *auto_width
-                } else {
-                    grid_width as usize
-                };
-                let dashes = "-".repeat(width + 2);
-                write!(self.output_file, "+{}", dashes)?;
-            }
-            write!(self.output_file, "+\n")?;
-        }
-        if self.grid_rows >= self.grid_rows_limit {
-            if self.grid_rows == self.grid_rows_limit {
-                write!(self.output_file, "------\nHit limit of {} rows\n", self.grid_rows_limit)?;
-                self.reload(true)?;
+        match self.output.row_action() {
+            RowAction::Skip => return Ok(false),
+            RowAction::Stop => {
+                if !self.output.hit_limit() {
+                    self.output.set_hit_limit();
+                    self.append_last()?;
+                    self.reload(true)?;
+                }
+                return Ok(true);
             }
-            return Ok(true);
+            RowAction::Keep => {}
         }
-        self.grid_rows += 1;
-        for (((column, value), empty), auto_width) in columns.iter()
+        let precision = self.output.settings.precision;
+        let cells = columns
+            .iter()
             .zip(values.iter())
             .zip(emptys.iter().map(|x| *x != 0))
-            .zip(self.auto_widths.iter_mut()) {
-            let grid_width = column.grid_width;
-            let width = if grid_width < 0 {
-                continue;
-            } else if grid_width == 0 {
-                *auto_width
-            } else {
-                grid_width as usize
-            };
-            let string_value = value.to_string(column.cell_type, empty);
-            write!(
-                self.output_file,
-                "| {val:>width$} ",
-                width = width,
-                val = string_value
-            )?;
-            *auto_width = std::cmp::max(*auto_width, string_value.len());
+            .map(|((column, value), empty)| value.to_string(column, empty, precision))
+            .collect();
+        if let Some(ref path) = self.export {
+            if !self.output.exporting() {
+                return Ok(true);
+            }
+            if let Err(e) = self.output.export_row(columns, cells) {
+                let failure = format!("Unable to export to {:?}: {}", path, e);
+                println!("{}", failure);
+                self.export_failure = Some(failure);
+                self.output.cancel_export();
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+        if self.output.push_row(columns, cells) {
+            self.rerender()?;
+        } else {
+            self.append_last()?;
         }
-        write!(self.output_file, "|\n")?;
         self.reload(false)?;
         Ok(false)
     }
 
-    fn write(&mut self, string: &str) -> Result<()> {
-        write!(self.output_file, "{}", string)?;
+    /// Rewrite the output file from the buffer, e.g. after a column widened
+    fn rerender(&mut self) -> Result<()> {
+        if self.export.is_some() {
+            return Ok(());
+        }
+        self.update_syntax()?;
+        self.output_file.set_len(0)?;
+        self.output_file.seek(SeekFrom::Start(0))?;
+        self.output.render(&mut self.output_file)?;
         Ok(())
     }
 
+    /// Append the most recent chunk of the buffer to the output file
+    fn append_last(&mut self) -> Result<()> {
+        if self.export.is_none() {
+            self.output.render_last(&mut self.output_file)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, string: &str) -> Result<()> {
+        self.output.push_text(string);
+        self.append_last()
+    }
+
     fn reset_output(&mut self) -> std::io::Result<()> {
-        self.output_file.set_len(0)?;
-        self.output_file.seek(SeekFrom::Start(0))?;
         self.log_file.set_len(0)?;
         self.log_file.seek(SeekFrom::Start(0))?;
-        self.grid_rows = 0;
+        for acknowledgement in self.acknowledgements.drain(..) {
+            println!("{}", acknowledgement);
+        }
+        self.output.clear();
+        self.output.settings = self.settings.clone();
+        // An export leaves the previous preview in place
+        if self.export.is_none() {
+            self.output_file.set_len(0)?;
+            self.output_file.seek(SeekFrom::Start(0))?;
+            self.update_syntax()?;
+        }
+        Ok(())
+    }
+
+    fn update_syntax(&mut self) -> std::io::Result<()> {
+        let syntax = self.output.syntax();
+        if syntax != self.syntax {
+            fs::write(&self.syntax_path, &syntax)?;
+            self.syntax = syntax;
+        }
+        Ok(())
+    }
+
+    /// Start writing the next run's rows to the file asked for, if any. If it can't be
+    /// created, the run is previewed as usual.
+    fn open_export(&mut self) {
+        if let Some(path) = self.export.take() {
+            let renderer = control::export_renderer(&path);
+            match self.output.start_export(&path, renderer, &self.workspace.join("export.spool")) {
+                Ok(()) => self.export = Some(path),
+                Err(e) => {
+                    let failure = format!("Unable to export to {:?}: {}", path, e);
+                    self.acknowledgements.push(failure.clone());
+                    self.export_failure = Some(failure);
+                }
+            }
+        }
+    }
+
+    /// Called once the script has returned
+    fn finish_output(&mut self) -> Result<()> {
+        if let Some(path) = self.export.take() {
+            if self.output.exporting() {
+                match self.output.finish_export() {
+                    Ok(()) => println!("Exported {} rows to {:?}", self.output.rows(), path),
+                    Err(e) => {
+                        let failure = format!("Unable to export to {:?}: {}", path, e);
+                        println!("{}", failure);
+                        self.export_failure = Some(failure);
+                    }
+                }
+            }
+        } else if self.output.finish() {
+            self.rerender()?;
+        }
+        if self.output.settings.renderer == Renderer::Csv {
+            if let Some(notice) = self.output.notice() {
+                println!("{}", notice);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_grid_rows_limit(&mut self, limit: usize) {
+        self.output.settings.limit = limit;
+    }
+
+    fn set_grid_max_width(&mut self, max_width: usize) {
+        self.settings.max_width = max_width;
+    }
+
+    fn set_default_precision(&mut self, precision: Option<usize>) {
+        self.settings.precision = precision;
+    }
+
+    fn set_renderer(&mut self, renderer: Renderer) {
+        self.settings.renderer = renderer;
+    }
+
+    /// Re-read the `settings` file; problems are reported in the log. If it can't be read,
+    /// the defaults are used.
+    fn load_settings(&mut self) {
+        self.overrides.clear();
+        let contents = fs::read_to_string(&self.settings_path).unwrap_or_else(|e| {
+            println!("Unable to read {:?}, using the default settings: {}", self.settings_path, e);
+            String::new()
+        });
+        let mut check = ViewSettings::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => {
+                    println!("settings:{}: expected `key = value`", i + 1);
+                    continue;
+                }
+            };
+            match check.set(key, value) {
+                Ok(()) => self.overrides.push((key.to_string(), value.to_string())),
+                Err(e) => println!("settings:{}: {}", i + 1, e),
+            }
+        }
+        self.overrides.extend(self.session_overrides.iter().cloned());
+        if let Some(ref path) = self.export {
+            self.overrides.push(("limit".to_string(), usize::MAX.to_string()));
+            self.overrides.push(("offset".to_string(), "0".to_string()));
+            self.overrides.push(("tail".to_string(), "false".to_string()));
+            self.overrides.push(("renderer".to_string(), control::export_renderer(path).to_string()));
+        }
+    }
+
+    fn apply_overrides(&mut self) {
+        for (key, value) in self.overrides.iter() {
+            self.output.settings.set(key, value).unwrap();
+        }
+    }
+
+    /// Change a setting from the script, unless it's overridden in the `settings` file
+    fn script_set(&mut self, key: &str, value: &str) -> bool {
+        if self.overrides.iter().any(|(k, _)| k == key) {
+            return true;
+        }
+        match self.output.settings.set(key, value) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("api_set: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Block until the script, the settings or the input change, requests arrive, or
+    /// `timeout` has passed
+    fn wait_for_changes(&mut self, timeout: Option<Duration>) -> Result<Changes> {
+        let mut fds = vec![
+            PollFd::new(self.notify.as_raw_fd(), EventFlags::POLLIN),
+            PollFd::new(self.control.source.as_raw_fd(), EventFlags::POLLIN),
+            PollFd::new(self.socket.as_raw_fd(), EventFlags::POLLIN),
+        ];
+        fds.push(PollFd::new(self.signals.as_raw_fd(), EventFlags::POLLIN));
+        if let Some(ref stream) = self.vim_exited {
+            fds.push(PollFd::new(stream.as_raw_fd(), EventFlags::POLLIN));
+        }
+        fds.extend(self.clients.iter().map(|c| PollFd::new(c.source.as_raw_fd(), EventFlags::POLLIN)));
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        let mut changes = Changes::default();
+        match poll(&mut fds, timeout) {
+            Ok(0) => changes.tick = true,
+            // Interrupted by a signal, which is read from the pipe below
+            Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut signal = [0];
+        if let Ok(1) = self.signals.read(&mut signal) {
+            changes.exit = Some(Exit::Signal(signal[0] as i32));
+            return Ok(changes);
+        }
+        if !self.vim_running() {
+            changes.exit = Some(Exit::EditorClosed);
+            return Ok(changes);
+        }
+
+        let mut buffer = [0; 1024];
+        for event in self.notify.read_events(&mut buffer)? {
+            if event.wd == self.script_watch {
+                changes.script = true;
+            } else if event.wd == self.settings_watch {
+                changes.settings = true;
+            } else if self
+                .input_watches
+                .iter()
+                .any(|(wd, name)| event.wd == *wd && event.name == Some(name.as_os_str()))
+            {
+                changes.input = true;
+            }
+        }
+
+        let mut lines = vec![];
+        self.control.read_lines(&mut lines)?;
+        changes.requests.extend(lines.drain(..).map(|line| (None, line)));
+
+        loop {
+            match self.socket.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.clients.push(LineReader::new(stream));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut i = 0;
+        while i < self.clients.len() {
+            // A client that fails is treated like one that hung up
+            let open = self.clients[i].read_lines(&mut lines).unwrap_or(false);
+            for line in lines.drain(..) {
+                changes.requests.push((Some(self.clients[i].source.try_clone()?), line));
+            }
+            if open {
+                i += 1;
+            } else {
+                self.clients.remove(i);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Re-run when any of `paths` changes. Directories are watched rather than files, so
+    /// a file that's replaced by renaming a new one over it is noticed too. Only regular
+    /// files are watched.
+    fn watch_input(&mut self, paths: &[path::PathBuf]) -> Result<()> {
+        for (wd, _) in self.input_watches.drain(..) {
+            // Files in the same directory share a watch, which may already be gone
+            let _ = self.notify.rm_watch(wd);
+        }
+        for path in paths {
+            if !fs::metadata(path).map(|m| m.is_file()).unwrap_or(false) {
+                continue;
+            }
+            let path = fs::canonicalize(path)?;
+            if let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+                let mask = inotify::WatchMask::MODIFY | inotify::WatchMask::CLOSE_WRITE | inotify::WatchMask::MOVED_TO;
+                let wd = self.notify.add_watch(dir, mask)?;
+                self.input_watches.push((wd, name.to_os_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// State of the last run, as JSON
+    fn status(&self, input: &CsvInputFile, loaded: bool) -> String {
+        let settings = &self.output.settings;
+        format!(
+            "{{\"ok\": true, \"loaded\": {}, \"input\": {}, \"rows\": {}, \"hit_limit\": {}, \
+             \"inputs\": [{}], \"diagnostics\": {}, \"limit\": {}, \"offset\": {}, \"tail\": {}, \"renderer\": \"{}\"}}",
+            loaded,
+            json_string(&input.paths()[0].to_string_lossy()),
+            self.output.rows(),
+            self.output.hit_limit(),
+            input.paths().iter().map(|p| json_string(&p.to_string_lossy())).collect::<Vec<_>>().join(", "),
+            input.diagnostics.count,
+            settings.limit,
+            settings.offset,
+            settings.tail,
+            settings.renderer
+        )
+    }
+
+    /// Send `response` to a client of the control socket, or to the log for requests from vim
+    fn respond(&self, client: Option<UnixStream>, response: &str) {
+        match client {
+            // The client may have gone away; there's nobody to tell
+            Some(mut stream) => {
+                let _ = writeln!(stream, "{}", response);
+            }
+            None => println!("{}", response),
+        }
+    }
+
+    /// End the session: close vim if it's still open, and remove the control FIFO and
+    /// socket, and with `clean` the compiled script. Returns a summary for the terminal.
+    fn shutdown(&mut self, exit: Option<&Exit>, clean: bool) -> String {
+        if self.vim_running() {
+            let _ = remote_send(&self.servername, "<Esc>:qa!<CR>");
+            let closed = match self.vim_exited {
+                Some(ref stream) => {
+                    let mut fds = [PollFd::new(stream.as_raw_fd(), EventFlags::POLLIN)];
+                    poll(&mut fds, 1000).map(|n| n > 0).unwrap_or(false)
+                }
+                None => true,
+            };
+            if let (false, Some(pid)) = (closed, self.vim_pid) {
+                let _ = signal::kill(nix::unistd::Pid::from_raw(pid as i32), Signal::SIGTERM);
+            }
+        }
+
+        let mut removed = vec![self.workspace.join("control"), self.workspace.join("control.sock")];
+        if clean {
+            removed.push(self.workspace.join("liblivid.so"));
+        }
+        for path in removed {
+            let _ = fs::remove_file(path);
+        }
+
+        let absolute = |name: &str| {
+            let path = self.workspace.join(name);
+            fs::canonicalize(&path).unwrap_or(path)
+        };
+        let reason = match exit {
+            Some(Exit::EditorClosed) => "vim exited".to_string(),
+            Some(Exit::Quit) => "quit requested".to_string(),
+            Some(&Exit::Signal(n)) => match Signal::from_c_int(n) {
+                Ok(s) => format!("caught {:?}", s),
+                Err(_) => format!("caught signal {}", n),
+            },
+            None => "stopped after an error".to_string(),
+        };
+        format!(
+            "livid: {}\n  Script: {}\n  Output: {} ({} rows)\n\
+             To save every row next time, run :LividExport <path> before quitting vim.",
+            reason,
+            absolute("script.c").display(),
+            absolute("output").display(),
+            self.output.rows()
+        )
+    }
+
+    fn switch_input(
+        &mut self,
+        input: &mut CsvInputFile,
+        opt: &Opt,
+        paths: Vec<path::PathBuf>,
+    ) -> std::result::Result<(), String> {
+        let new_input = open_input(&paths, opt, None).map_err(|e| format!("Unable to open {:?}: {}", paths, e))?;
+        self.acknowledgements.push(format!("Reading from {:?}", paths));
+        if let Err(e) = self.watch_input(&paths) {
+            self.acknowledgements.push(format!("Unable to watch {:?}: {}", paths, e));
+        }
+        *input = new_input;
         Ok(())
     }
 
-    fn set_grid_rows_limit(&mut self, limit: usize) -> () {
-        self.grid_rows_limit = limit;
+    /// Run `--cmd` again and read its output, which is always written to the workspace,
+    /// even after `:LividInput` switched to reading another file
+    fn rerun_command(&self, command: &str, opt: &Opt) -> Result<CsvInputFile> {
+        let output = self.workspace.join("input.csv");
+        run_command(command, &output)?;
+        open_input(&[output], opt, None)
+    }
+
+    /// Apply a request; it takes effect in the next run. Returns why it couldn't be, for
+    /// the client.
+    fn handle_request(&mut self, input: &mut CsvInputFile, opt: &Opt, request: Request) -> std::result::Result<(), String> {
+        match request {
+            Request::Rerun => self.acknowledgements.push("Rerunning".to_string()),
+            Request::Set(key, value) => {
+                self.acknowledgements.push(format!("Set {} = {}", key, value));
+                self.session_overrides.push((key, value));
+            }
+            Request::Export(path) => {
+                // Checked now so the client hears about it; it's created again by the run
+                File::create(&path).map_err(|e| format!("Unable to export to {:?}: {}", path, e))?;
+                self.export = Some(path);
+            }
+            Request::Input(path) => self.switch_input(input, opt, vec![path])?,
+            Request::ReloadInput => match opt.command {
+                // Run the command again rather than rereading its last output
+                Some(ref command) => {
+                    *input = self
+                        .rerun_command(command, opt)
+                        .map_err(|e| format!("Unable to run {:?}: {}", command, e))?;
+                    self.acknowledgements.push(format!("Ran {:?}", command));
+                }
+                None => self.switch_input(input, opt, input.paths())?,
+            },
+            // Handled without running the script
+            Request::Status | Request::Quit | Request::Attach(_) | Request::Detach(_) => {}
+        }
+        Ok(())
     }
 }
 
-fn run_livid(mut editor: Editor, mut input: CsvInputFile) -> Result<()> {
-    generate_script(&mut editor.script_file, input.input_columns())?;
-    let _editor_jh = editor.launch()?;
+/// Why a session ended
+#[derive(Debug, PartialEq)]
+enum Exit {
+    EditorClosed,
+    Signal(i32),
+    Quit,
+}
+
+/// What happened while waiting between runs
+#[derive(Default)]
+struct Changes {
+    exit: Option<Exit>,
+    script: bool,
+    settings: bool,
+    input: bool,
+    /// Time to run the `--cmd` command again
+    tick: bool,
+    /// Lines read from the control FIFO, or from a client of the control socket
+    requests: Vec<(Option<UnixStream>, String)>,
+}
+
+fn run_livid(editor: &mut Editor, mut input: CsvInputFile, lookups: &[Lookup], opt: &Opt) -> Result<Exit> {
+    if !opt.resume || editor.script_file.metadata()?.len() == 0 {
+        generate_script(&mut editor.script_file, input.input_columns(), lookups)?;
+    }
+    // The output of a command is refreshed on a timer instead
+    if opt.command.is_none() {
+        editor.watch_input(&input.paths())?;
+    }
+    editor.launch()?;
+    let mut container: Option<Container<LividLib<'static>>> = None;
+    // A new session shows a profile of the input until the script is first saved
+    let mut recompile = opt.resume || opt.no_profile;
+    let mut compiled = false;
+    let mut profile: Option<String> = None;
+    let mut next_refresh = opt.every.map(|every| Instant::now() + every);
     loop {
+        editor.open_export();
         editor.reset_output()?;
-        let lib_path = editor.compile()?;
-
-        println!("Compiled: {:?}", lib_path);
-        {
-            let api = LividApi::new(&mut input, &mut editor);
-            let container: Container<LividLib> = unsafe { Container::load(lib_path) }.unwrap();
+        editor.load_settings();
+        if recompile {
+            // The old library has to be unloaded before the new one is loaded over it
+            container.take();
+            compiled = true;
+            let lib_path = editor.compile()?;
+            println!("Compiled: {:?}", lib_path);
+            match unsafe { Container::load(lib_path) } {
+                Ok(c) => container = Some(c),
+                Err(e) => println!("Unable to load script: {}", e),
+            }
+        }
+        if let Some(ref container) = container {
+            let mut api = LividApi::new(&mut input, editor, lookups);
             println!(
                 "Loaded container: {:?} {:?}",
                 container.columns, container.columns_count
             );
             api.editor.set_grid_rows_limit(*container.grid_rows_limit);
+            api.editor.apply_overrides();
 
-            let output_columns =
+            let output_columns: Vec<Column> =
                 unsafe { slice::from_raw_parts(container.columns, *container.columns_count) }
                     .iter()
                     .enumerate()
                     .map(|(i, c)| { Column::from_c(*c, i) }) 
                     .collect();
             println!("Columns: {:?}", output_columns);
+            for column in output_columns.iter() {
+                if let Some(ref default) = column.default_value {
+                    if column.parse_raw(default).is_none() {
+                        println!(
+                            "Warning: default {:?} for column {:?} is not a valid {}",
+                            default, column.name, column.type_str()
+                        );
+                    }
+                }
+            }
             api.input.set_output_columns(output_columns);
             api.input.reset();
-            container.run(&api);
+            // The library outlives the run, so its signature can't borrow `api`'s lifetime
+            container.run(&mut api as *mut LividApi as *mut _);
+            api.editor.finish_output()?;
+            api.input.report_diagnostics();
+        } else if !compiled {
+            let profile = profile.get_or_insert_with(|| Profile::read(&mut input).to_string());
+            editor.write(&format!("Profile of the input; save the script to run it\n\n{}", profile))?;
         }
+        // Without a script to run, an export waits for the next run
+        editor.output.cancel_export();
 
         editor.reload(true).unwrap();
+        let status = match editor.export_failure.take() {
+            Some(failure) => json_error(&failure),
+            None => editor.status(&input, container.is_some()),
+        };
+        for mut stream in editor.waiting.drain(..) {
+            let _ = writeln!(stream, "{}", status);
+        }
 
-        let mut buffer = [0; 1024];
-        let _events = editor.script_notify.read_events_blocking(&mut buffer);
+        loop {
+            let timeout = next_refresh.map(|at| at.saturating_duration_since(Instant::now()));
+            let changes = editor.wait_for_changes(timeout)?;
+            if let Some(exit) = changes.exit {
+                return Ok(exit);
+            }
+            if changes.tick {
+                if let (Some(ref command), Some(every)) = (&opt.command, opt.every) {
+                    next_refresh = Some(Instant::now() + every);
+                    match editor.rerun_command(command, opt) {
+                        Ok(new_input) => {
+                            input = new_input;
+                            profile = None;
+                        }
+                        Err(e) => editor.acknowledgements.push(format!("Unable to run {:?}: {}", command, e)),
+                    }
+                }
+            }
+            let mut rerun = changes.script || changes.settings || changes.input || changes.tick;
+            recompile = changes.script;
+            // When following, rows appended since the last run are read by the next one
+            if changes.input && !(opt.follow && input.only_appended()) {
+                let paths = input.paths();
+                match open_input(&paths, opt, None) {
+                    Ok(new_input) => input = new_input,
+                    Err(e) => editor.acknowledgements.push(format!("Unable to reopen {:?}: {}", paths, e)),
+                }
+                profile = None;
+            }
+            let answered = !changes.requests.is_empty();
+            for (client, line) in changes.requests {
+                match line.parse() {
+                    Ok(Request::Status) => {
+                        let status = editor.status(&input, container.is_some());
+                        editor.respond(client, &status);
+                    }
+                    Ok(Request::Quit) => {
+                        editor.respond(client, "{\"ok\": true}");
+                        return Ok(Exit::Quit);
+                    }
+                    Ok(Request::Attach(servername)) => {
+                        editor.attached.push(servername);
+                        editor.respond(client, "{\"ok\": true}");
+                    }
+                    Ok(Request::Detach(servername)) => {
+                        editor.attached.retain(|s| *s != servername);
+                        editor.respond(client, "{\"ok\": true}");
+                    }
+                    Ok(request) => {
+                        if matches!(request, Request::Input(_) | Request::ReloadInput) {
+                            profile = None;
+                        }
+                        match editor.handle_request(&mut input, opt, request) {
+                            Ok(()) => {
+                                editor.waiting.extend(client);
+                                rerun = true;
+                            }
+                            Err(e) => match client {
+                                Some(_) => editor.respond(client, &json_error(&e)),
+                                None => println!("{}", e),
+                            },
+                        }
+                    }
+                    Err(e) => match client {
+                        Some(_) => editor.respond(client, &json_error(&e)),
+                        None => println!("Request {:?}: {}", line, e),
+                    },
+                }
+            }
+            if rerun {
+                break;
+            }
+            // Show any errors printed for requests
+            if answered {
+                editor.reload(true)?;
+            }
+        }
     }
 }
 
-fn generate_script(file: &mut File, columns: &Vec<Column>) -> Result<()> {
+fn generate_script(file: &mut File, columns: &[Column], lookups: &[Lookup]) -> Result<()> {
     file.set_len(0)?;
-    write!(file, "#define COLUMN_LIST \\\n")?;
-    write!(
+    writeln!(file, "#define COLUMN_LIST \\")?;
+    write_column_list(file, columns)?;
+    writeln!(file)?;
+
+    if !lookups.is_empty() {
+        writeln!(file, "#define LOOKUP_LIST \\")?;
+        for lookup in lookups {
+            writeln!(file, "    LOOKUP({}) \\", lookup.name)?;
+        }
+        writeln!(file)?;
+        for lookup in lookups {
+            writeln!(file, "// Columns of `struct lookup_{}`, keyed by {}", lookup.name, lookup.key)?;
+            writeln!(file, "#define LOOKUP_{}(COLUMN) \\", lookup.name)?;
+            write_column_list(file, &lookup.columns)?;
+            writeln!(file)?;
+        }
+    }
+
+    file.write_all(include_str!("../c_src/template.c").as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn write_column_list(file: &mut File, columns: &[Column]) -> Result<()> {
+    writeln!(
         file,
-        "    /*     {:16}  {:10}  {:10} */\\\n",
+        "    /*     {:16}  {:10}  {:10} */\\",
         "column name", "type", "grid width"
     )?;
     for column in columns {
-        write!(
+        writeln!(
             file,
-            "    COLUMN({:16}, {:10}, {:10}) \\\n",
+            "    COLUMN({:16}, {:10}, {:10}) \\",
             column.name.to_str().unwrap(),
-            column.cell_type.upper_str(),
+            column.type_str(),
             "GRID_AUTO"
         )?;
     }
-    write!(file, "\n")?;
+    Ok(())
+}
 
-    file.write_all(include_str!("../c_src/template.c").as_bytes())?;
-    file.sync_all()?;
+fn remote_send(servername: &str, keys: &str) -> io::Result<()> {
+    Command::new("vim")
+        .arg("--servername")
+        .arg(servername)
+        .arg("--remote-send")
+        .arg(keys)
+        .status()?;
     Ok(())
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
-    let editor = Editor::new()?;
-    let input = CsvInputFile::new(&opt.input, opt.delimiter)?;
+    match opt.subcommand {
+        Some(Subcommand::List) => return list_sessions(),
+        Some(Subcommand::Attach { ref session }) => return attach(session.as_ref().map(|s| s.as_str())),
+        Some(Subcommand::Profile { ref input }) => return profile(input, &opt),
+        None => {}
+    }
+    let saved_input = path::Path::new(DEFAULT_WORKSPACE).join("input.csv");
+    let mut input_paths = if !opt.input.is_empty() {
+        expand_globs(&opt.input)?
+    } else if let Some(ref command) = opt.command {
+        // Listed as the session's input; the output is read from the workspace
+        vec![path::PathBuf::from(command)]
+    } else if opt.resume {
+        if !saved_input.exists() {
+            return Err(format!("nothing to resume: {:?} doesn't exist", saved_input).into());
+        }
+        if partial_marker(&saved_input).exists() {
+            println!(
+                "Warning: {:?} only has the rows read before the last session ended, not all of its input",
+                saved_input
+            );
+        }
+        vec![saved_input]
+    } else {
+        vec![path::PathBuf::from("/dev/stdin")]
+    };
+    let mut session = Session::create(&input_paths[0], opt.resume)?;
+    let mut copy = None;
+    if opt.command.is_some() {
+        input_paths = vec![session.workspace.join("input.csv")];
+    } else if !fs::metadata(&input_paths[0]).map(|m| m.is_file()).unwrap_or(false) {
+        // Input that can't be read again, like a pipe, is saved in the workspace
+        copy = Some(InputCopy::create(&session.workspace.join("input.csv"))?);
+    }
+    // Opened before stdio is redirected, so problems are shown on the terminal
+    let ran = match opt.command {
+        Some(ref command) => {
+            run_command(command, &input_paths[0]).map_err(|e| format!("Unable to run {:?}: {}", command, e).into())
+        }
+        None => Ok(()),
+    };
+    let opened = ran.and_then(|_| open_input(&input_paths, &opt, copy)).and_then(|input| {
+        let lookups = opt
+            .lookups
+            .iter()
+            .map(|spec| Lookup::load(spec, opt.delimiter, opt.infer_rows))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((input, lookups))
+    });
+    let (input, lookups) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            session.end();
+            return Err(e);
+        }
+    };
+    let mut editor = Editor::new(&session, opt.resume)?;
+    editor.set_grid_max_width(opt.max_width);
+    editor.set_default_precision(opt.precision);
+    editor.set_renderer(opt.renderer);
     println!("Header: {:#?}", input.input_columns());
 
-    run_livid(editor, input)
+    let result = run_livid(&mut editor, input, &lookups, &opt);
+    let summary = editor.shutdown(result.as_ref().ok(), opt.clean);
+    // Restores stdio
+    drop(editor);
+    session.end();
+    println!("{}", summary);
+    if let Exit::Signal(n) = result? {
+        process::exit(128 + n);
+    }
+    Ok(())
+}
+
+fn list_sessions() -> Result<()> {
+    let sessions = Session::list()?;
+    if sessions.is_empty() {
+        println!("No livid sessions are running");
+        return Ok(());
+    }
+    println!("{:8}  {:14}  {:40}  INPUT", "PID", "SERVER", "WORKSPACE");
+    for session in sessions {
+        println!(
+            "{:8}  {:14}  {:40}  {}",
+            session.pid,
+            session.servername,
+            session.workspace.display(),
+            session.input.display()
+        );
+    }
+    Ok(())
+}
+
+fn profile(input: &[path::PathBuf], opt: &Opt) -> Result<()> {
+    let paths = if input.is_empty() {
+        vec![path::PathBuf::from("/dev/stdin")]
+    } else {
+        expand_globs(input)?
+    };
+    let mut input = open_input(&paths, opt, None)?;
+    print!("{}", Profile::read(&mut input));
+    Ok(())
+}
+
+/// Open another vim on a running session's workspace, which the session reloads too
+fn attach(name: Option<&str>) -> Result<()> {
+    let session = Session::find(name)?;
+    let servername = format!("{}-{}", session.servername, process::id());
+    let request = |line: String| -> Result<()> {
+        let mut socket = UnixStream::connect(session.workspace.join("control.sock"))?;
+        writeln!(socket, "{}", line)?;
+        let mut response = String::new();
+        io::BufReader::new(socket).read_line(&mut response)?;
+        Ok(())
+    };
+    request(format!("attach {}", servername))?;
+    let status = Command::new("vim")
+        .arg("--servername")
+        .arg(&servername)
+        .arg("-S")
+        .arg(session.workspace.join("vimrc"))
+        .status();
+    // The session may have ended while vim was open
+    let _ = request(format!("detach {}", servername));
+    status?;
+    Ok(())
+}
+
+/// The write end of the pipe that signals are reported on
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn livid_signal_handler(signal: libc::c_int) {
+    let byte = signal as u8;
+    unsafe {
+        libc::write(SIGNAL_PIPE.load(Ordering::Relaxed), &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+/// Report SIGINT and SIGTERM on a pipe, so they can be handled between runs. A second
+/// signal gets the default action, in case a script is stuck in a loop.
+fn catch_signals() -> Result<File> {
+    let (read_fd, write_fd) = nix::unistd::pipe2(nix::fcntl::OFlag::O_NONBLOCK | nix::fcntl::OFlag::O_CLOEXEC)?;
+    SIGNAL_PIPE.store(write_fd, Ordering::Relaxed);
+    let action = SigAction::new(
+        SigHandler::Handler(livid_signal_handler),
+        SaFlags::SA_RESETHAND | SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe {
+        signal::sigaction(Signal::SIGINT, &action)?;
+        signal::sigaction(Signal::SIGTERM, &action)?;
+        Ok(File::from_raw_fd(read_fd))
+    }
+}
+
+/// Run `command` with its output going to `path`, to be read as input. Its errors go to
+/// the log. The output is written next to `path` and only replaces it if the command
+/// succeeds, so a failed run leaves the last output, which may still be being read.
+fn run_command(command: &str, path: &path::Path) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".new");
+    let partial = path::PathBuf::from(partial);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(File::create(&partial)?)
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(fs::rename(&partial, path)?),
+        Ok(status) => {
+            let _ = fs::remove_file(&partial);
+            Err(format!("command failed: {}", status).into())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e.into())
+        }
+    }
+}
+
+/// Parse an interval like `500ms`, `2s`, `1.5m` or `1h`; a bare number is in seconds
+fn parse_interval(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let unit = match &s[split..] {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        unit => return Err(format!("unknown unit {:?} (use ms, s, m or h)", unit)),
+    };
+    match s[..split].parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(Duration::from_secs_f64(n * unit)),
+        _ => Err(format!("{:?} is not a positive interval", s)),
+    }
+}
+
+/// Expand the arguments that are glob patterns rather than files, for shells that pass
+/// them on unexpanded or when they're quoted. Each pattern's matches are sorted, so
+/// files named by date are read in order.
+fn expand_globs(args: &[path::PathBuf]) -> Result<Vec<path::PathBuf>> {
+    let mut paths = vec![];
+    for arg in args {
+        let pattern = arg.to_string_lossy();
+        if arg.exists() || !pattern.contains(['*', '?', '[']) {
+            paths.push(arg.clone());
+            continue;
+        }
+        let mut matches = glob::glob(&pattern)?.collect::<std::result::Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            return Err(format!("no files match {:?}", pattern).into());
+        }
+        matches.sort();
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+fn open_input(paths: &[path::PathBuf], opt: &Opt, copy: Option<InputCopy>) -> Result<CsvInputFile> {
+    let mut input = CsvInputFile::new(paths, opt.delimiter, !opt.no_header, opt.columns, copy)?;
+    input.reader.follow = opt.follow;
+    for source in input.reader.sources.iter() {
+        if source.compression != Compression::None {
+            println!("Reading {} compressed input from {:?}", source.compression, source.path);
+        }
+    }
+    input.infer_types(opt.infer_rows);
+    Ok(input)
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "livid")]
 struct Opt {
-    /// Input CSV file
-    #[structopt(name = "file", default_value = "/dev/stdin", parse(from_os_str))]
-    input: path::PathBuf,
-
+    /// Input CSV files or glob patterns, read one after the other as a single table; the
+    /// columns are the union of their headers, and scripts can add `_source_file` and
+    /// `_line` columns to see where rows came from [default: standard input, or the saved
+    /// input with --resume]
+    #[structopt(name = "file", parse(from_os_str))]
+    input: Vec<path::PathBuf>,
+
+    /// Field delimiter; a space splits on runs of whitespace, with the last column taking
+    /// the rest of the line
     #[structopt(short = "d", long = "delimiter", default_value = ",")]
     delimiter: char,
+
+    /// The first line is a row rather than a header; columns are named c1, c2, ...
+    #[structopt(long = "no-header")]
+    no_header: bool,
+
+    /// Number of columns with --no-header, which a space delimiter needs, as the last one
+    /// takes the rest of the line; otherwise it's the number of fields in the first row
+    #[structopt(long = "columns", requires = "no_header")]
+    columns: Option<usize>,
+
+    /// Read the output of a shell command instead of a file
+    #[structopt(long = "cmd", conflicts_with = "file")]
+    command: Option<String>,
+
+    /// Run the --cmd command again this often, like `watch`: 500ms, 2s, 1m
+    #[structopt(long = "every", requires = "command", parse(try_from_str = "parse_interval"))]
+    every: Option<Duration>,
+
+    /// Load another file to join rows against, indexed by its key column:
+    /// `--lookup users=users.csv:id` makes `api_lookup(api, users, key, &user)` available
+    #[structopt(long = "lookup", number_of_values = 1)]
+    lookups: Vec<LookupSpec>,
+
+    /// Number of rows to sample when guessing column types (0 to leave all columns as TEXT)
+    #[structopt(long = "infer-rows", default_value = "100")]
+    infer_rows: usize,
+
+    /// Maximum width of auto-width grid columns; longer values are truncated (0 for no limit)
+    #[structopt(long = "max-width", default_value = "0")]
+    max_width: usize,
+
+    /// Number of decimal places shown for DOUBLE columns without their own FMT_PRECISION
+    #[structopt(long = "precision")]
+    precision: Option<usize>,
+
+    /// How the grid is drawn: ascii, unicode, markdown, csv or expanded
+    #[structopt(long = "renderer", default_value = "ascii")]
+    renderer: Renderer,
+
+    /// Remove the compiled script from the workspace on exit
+    #[structopt(long = "clean")]
+    clean: bool,
+
+    /// Keep rows already read when the input file is appended to, and read just the new
+    /// ones, like `tail -f`; combine with `tail = true` in the settings to see the latest
+    #[structopt(long = "follow")]
+    follow: bool,
+
+    /// Pick up where the last session in ./wkspace left off, keeping its script and
+    /// settings, and reading its saved input if no file is given
+    #[structopt(long = "resume")]
+    resume: bool,
+
+    /// Run the script straight away, rather than showing a profile of the input until it's
+    /// first saved
+    #[structopt(long = "no-profile")]
+    no_profile: bool,
+
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// List running livid sessions
+    #[structopt(name = "list")]
+    List,
+    /// Open another vim on a running session
+    #[structopt(name = "attach")]
+    Attach {
+        /// PID or server name from `livid list`; may be left out if only one session is running
+        session: Option<String>,
+    },
+    /// Print each input column's type, empty and distinct counts, range, example values
+    /// and most common values, using the options given before `profile`
+    #[structopt(name = "profile")]
+    Profile {
+        /// Input CSV files or glob patterns [default: standard input]
+        #[structopt(name = "file", parse(from_os_str))]
+        input: Vec<path::PathBuf>,
+    },
 }
 
@@ -0,0 +1,119 @@
+//! Hash maps and sets for scripts, owned by the run and freed when `run` returns. Keys
+//! are strings or numbers, values are `long`s, and entries are kept in the order they
+//! were first inserted, so iterating shows the input's order.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr;
+
+use super::{const_char_cstr, LividApi};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Text(CString),
+    Number(i64),
+}
+
+#[derive(Default)]
+pub struct Map {
+    entries: Vec<(Key, i64)>,
+    index: HashMap<Key, usize>,
+}
+
+/// Values of `op` for `api->map_update`, matching `enum map_op` in livid.h
+const MAP_GET: i8 = 0;
+const MAP_PUT: i8 = 1;
+const MAP_ADD: i8 = 2;
+
+/// The key passed from C: the string if `text_key` is set, otherwise the number. A NULL
+/// string, like a missing field's, is the empty string rather than a number.
+pub unsafe fn key(text_key: bool, text: *const c_char, number: i64) -> Key {
+    match (text_key, text.is_null()) {
+        (false, _) => Key::Number(number),
+        (true, true) => Key::Text(CString::default()),
+        (true, false) => Key::Text(const_char_cstr(text).to_owned()),
+    }
+}
+
+pub extern "C" fn livid_api_raw_map_new<'a>(api: *mut LividApi<'a>) -> *mut Map {
+    unsafe {
+        let maps = &mut (*api).maps;
+        maps.push(Box::default());
+        &mut **maps.last_mut().unwrap()
+    }
+}
+
+/// Look up a key and, depending on `op`, set it or add to it. `*value_out` is set to the
+/// value afterwards, if there is one. Returns whether the key was already in the map.
+pub extern "C" fn livid_api_raw_map_update<'a>(
+    _api: *mut LividApi<'a>,
+    map: *mut Map,
+    text_key: bool,
+    text: *const c_char,
+    number: i64,
+    op: i8,
+    value: i64,
+    value_out: *mut i64,
+) -> i8 {
+    unsafe {
+        let map = &mut *map;
+        let key = key(text_key, text, number);
+        let found = map.index.get(&key).cloned();
+        let slot = match (found, op) {
+            (Some(i), _) => &mut map.entries[i].1,
+            (None, MAP_PUT) | (None, MAP_ADD) => {
+                map.index.insert(key.clone(), map.entries.len());
+                map.entries.push((key, 0));
+                &mut map.entries.last_mut().unwrap().1
+            }
+            (None, _) => return 0,
+        };
+        match op {
+            MAP_PUT => *slot = value,
+            MAP_ADD => *slot = slot.wrapping_add(value),
+            _ => {}
+        }
+        if !value_out.is_null() {
+            value_out.write(*slot);
+        }
+        found.is_some() as i8
+    }
+}
+
+pub extern "C" fn livid_api_raw_map_len<'a>(_api: *mut LividApi<'a>, map: *const Map) -> usize {
+    unsafe { (&*map).entries.len() }
+}
+
+/// The `index`th entry, in insertion order. Text keys are returned in `*text_out`, which
+/// is NULL for number keys. Returns 0 past the end.
+pub extern "C" fn livid_api_raw_map_entry<'a>(
+    _api: *mut LividApi<'a>,
+    map: *const Map,
+    index: usize,
+    text_out: *mut *const c_char,
+    number_out: *mut i64,
+    value_out: *mut i64,
+) -> i8 {
+    unsafe {
+        let map = &*map;
+        let (key, value) = match map.entries.get(index) {
+            Some(entry) => entry,
+            None => return 0,
+        };
+        let (text, number) = match *key {
+            Key::Text(ref text) => (text.as_ptr(), 0),
+            Key::Number(number) => (ptr::null(), number),
+        };
+        if !text_out.is_null() {
+            text_out.write(text);
+        }
+        if !number_out.is_null() {
+            number_out.write(number);
+        }
+        if !value_out.is_null() {
+            value_out.write(*value);
+        }
+        1
+    }
+}
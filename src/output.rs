@@ -0,0 +1,844 @@
+//! The contents of the output buffer: text printed by the script, interleaved with rows
+//! of the grid. Everything written during a run is kept, so when a new row widens a
+//! column the whole buffer can be re-rendered with every row aligned.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use unicode_width::UnicodeWidthStr;
+
+use {escape_invalid_utf8, parse_bool, truncate_to_width, CellType, Column};
+
+/// Preview settings. They start from the command line, can be changed by the script
+/// (`grid_rows_limit`, `api_set`), and can be overridden at runtime.
+#[derive(Clone, Debug)]
+pub struct ViewSettings {
+    /// Number of rows shown
+    pub limit: usize,
+    /// Number of rows skipped before the first one shown
+    pub offset: usize,
+    /// Show the last `limit` rows rather than the first
+    pub tail: bool,
+    pub max_width: usize,
+    pub precision: Option<usize>,
+    pub renderer: Renderer,
+}
+
+/// How the grid is drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Renderer {
+    /// `| a | b |`
+    Ascii,
+    /// `│ a │ b │`
+    Unicode,
+    /// A GitHub-flavoured Markdown table
+    Markdown,
+    /// Comma-separated values, not truncated or padded
+    Csv,
+    /// One field per line, like psql's `\x`
+    Expanded,
+}
+
+impl fmt::Display for Renderer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Renderer::Ascii => "ascii",
+            Renderer::Unicode => "unicode",
+            Renderer::Markdown => "markdown",
+            Renderer::Csv => "csv",
+            Renderer::Expanded => "expanded",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Renderer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "ascii" => Ok(Renderer::Ascii),
+            "unicode" => Ok(Renderer::Unicode),
+            "markdown" => Ok(Renderer::Markdown),
+            "csv" => Ok(Renderer::Csv),
+            "expanded" => Ok(Renderer::Expanded),
+            _ => Err(format!(
+                "unknown renderer {:?} (expected ascii, unicode, markdown, csv or expanded)",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for ViewSettings {
+    fn default() -> Self {
+        ViewSettings {
+            limit: 20,
+            offset: 0,
+            tail: false,
+            max_width: 0,
+            precision: None,
+            renderer: Renderer::Ascii,
+        }
+    }
+}
+
+impl ViewSettings {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid value {:?} for {}", value, key))
+        }
+        match key {
+            "limit" => self.limit = parse(key, value)?,
+            "offset" => self.offset = parse(key, value)?,
+            "tail" => {
+                self.tail = parse_bool(value).ok_or_else(|| format!("invalid value {:?} for {}", value, key))?
+            }
+            "max_width" => self.max_width = parse(key, value)?,
+            "precision" => {
+                self.precision = match value {
+                    "" | "none" => None,
+                    _ => Some(parse(key, value)?),
+                }
+            }
+            "renderer" => self.renderer = value.parse()?,
+            _ => return Err(format!("unknown setting {:?}", key)),
+        }
+        Ok(())
+    }
+}
+
+enum Chunk {
+    Text(String),
+    /// A row and its position in the grid's input, counting from 1
+    Row(usize, Vec<String>),
+    /// Where the rows kept in tail mode are shown
+    Tail,
+    Banner,
+}
+
+#[derive(Clone, Copy)]
+enum Align {
+    Left,
+    Right,
+}
+
+/// Rows written to a file as they're added, rather than kept in the buffer
+struct Export {
+    file: BufWriter<File>,
+    /// Renderers that pad cells need every row's width first, so until the run finishes
+    /// their rows are kept here, as NUL-terminated cells
+    spool: Option<(PathBuf, BufWriter<File>)>,
+}
+
+struct GridColumn {
+    name: String,
+    grid_width: i16,
+    cell_type: CellType,
+    align: Align,
+}
+
+impl GridColumn {
+    /// Display width of the column, or `None` if it's hidden
+    fn width(&self, auto_width: usize) -> Option<usize> {
+        match self.grid_width {
+            w if w < 0 => None,
+            0 => Some(auto_width),
+            w => Some(w as usize),
+        }
+    }
+}
+
+/// What to do with the next row passed to the grid
+pub enum RowAction {
+    /// Before the offset; drop it and carry on
+    Skip,
+    Keep,
+    /// The limit has been reached; the script should stop
+    Stop,
+}
+
+pub struct OutputBuffer {
+    chunks: Vec<Chunk>,
+    columns: Vec<GridColumn>,
+    auto_widths: Vec<usize>,
+    /// Rows passed to the grid, including skipped ones
+    seen_rows: usize,
+    /// Rows kept for display
+    rows: usize,
+    tail: VecDeque<(usize, Vec<String>)>,
+    hit_limit: bool,
+    /// Where rows go instead, while exporting
+    export: Option<Export>,
+    pub settings: ViewSettings,
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        OutputBuffer {
+            chunks: vec![],
+            columns: vec![],
+            auto_widths: vec![],
+            seen_rows: 0,
+            rows: 0,
+            tail: VecDeque::new(),
+            hit_limit: false,
+            export: None,
+            settings: ViewSettings::default(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.columns.clear();
+        self.auto_widths.clear();
+        self.seen_rows = 0;
+        self.rows = 0;
+        self.tail.clear();
+        self.hit_limit = false;
+    }
+
+    pub fn row_action(&mut self) -> RowAction {
+        if self.settings.tail {
+            self.seen_rows += 1;
+            RowAction::Keep
+        } else if self.seen_rows < self.settings.offset {
+            self.seen_rows += 1;
+            RowAction::Skip
+        } else if self.rows >= self.settings.limit {
+            RowAction::Stop
+        } else {
+            self.seen_rows += 1;
+            RowAction::Keep
+        }
+    }
+
+    pub fn push_text(&mut self, text: &str) {
+        self.chunks.push(Chunk::Text(text.to_string()));
+    }
+
+    /// Add a row of formatted cells. Returns `true` if the header or a column width
+    /// changed, so the whole buffer needs to be re-rendered rather than appended to.
+    pub fn push_row(&mut self, columns: &[Column], cells: Vec<String>) -> bool {
+        let first = self.rows == 0;
+        let mut changed = false;
+        if first {
+            self.columns = columns
+                .iter()
+                .map(|c| GridColumn {
+                    name: self.prepare(c.grid_width, escape_invalid_utf8(c.name.to_bytes())),
+                    grid_width: c.grid_width,
+                    cell_type: c.cell_type,
+                    align: match c.cell_type {
+                        CellType::Text | CellType::Bool => Align::Left,
+                        _ => Align::Right,
+                    },
+                }).collect();
+            self.auto_widths = self.columns.iter().map(|c| c.name.width()).collect();
+            if self.settings.tail {
+                self.chunks.push(Chunk::Tail);
+            }
+        }
+        let cells: Vec<String> = cells
+            .into_iter()
+            .zip(self.columns.iter())
+            .map(|(cell, column)| self.prepare(column.grid_width, cell))
+            .collect();
+        let number = self.seen_rows;
+        self.rows += 1;
+        if self.settings.tail {
+            // Widths are worked out from the rows that are left once the run finishes
+            self.tail.push_back((number, cells));
+            if self.tail.len() > self.settings.limit {
+                self.tail.pop_front();
+            }
+            return first;
+        }
+        for (cell, auto_width) in cells.iter().zip(self.auto_widths.iter_mut()) {
+            if cell.width() > *auto_width {
+                *auto_width = cell.width();
+                changed = true;
+            }
+        }
+        self.chunks.push(Chunk::Row(number, cells));
+        // CSV isn't padded, so wider cells don't need it to be re-rendered
+        first || (changed && self.settings.renderer != Renderer::Csv)
+    }
+
+    /// Write the rows of the next run to `path`; `spool` is a scratch file for renderers
+    /// that pad cells
+    pub fn start_export(&mut self, path: &Path, renderer: Renderer, spool: &Path) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let spool = match renderer {
+            Renderer::Csv => None,
+            _ => {
+                let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(spool)?;
+                Some((spool.to_path_buf(), BufWriter::new(file)))
+            }
+        };
+        self.export = Some(Export { file, spool });
+        Ok(())
+    }
+
+    pub fn exporting(&self) -> bool {
+        self.export.is_some()
+    }
+
+    /// Add a row to the export; it's written out now, or by `finish_export` if it has
+    /// to be padded
+    pub fn export_row(&mut self, columns: &[Column], cells: Vec<String>) -> io::Result<()> {
+        self.push_row(columns, cells);
+        let (number, cells) = match self.chunks.pop() {
+            Some(Chunk::Row(number, cells)) => (number, cells),
+            _ => return Ok(()),
+        };
+        let mut export = match self.export.take() {
+            Some(export) => export,
+            None => return Ok(()),
+        };
+        let written = match export.spool {
+            Some((_, ref mut spool)) => cells.iter().try_for_each(|cell| write!(spool, "{}\0", cell)),
+            None if self.rows == 1 => {
+                self.render_header(&mut export.file).and_then(|_| self.render_row(&mut export.file, number, &cells))
+            }
+            None => self.render_row(&mut export.file, number, &cells),
+        };
+        self.export = Some(export);
+        written
+    }
+
+    /// Stop exporting, leaving what was written so far
+    pub fn cancel_export(&mut self) {
+        if let Some(Export { spool: Some((path, _)), .. }) = self.export.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Write out any spooled rows, now their widths are known, and close the export
+    pub fn finish_export(&mut self) -> io::Result<()> {
+        let mut export = match self.export.take() {
+            Some(export) => export,
+            None => return Ok(()),
+        };
+        if let Some((path, spool)) = export.spool.take() {
+            let spooled = self.render_spool(&mut export.file, spool);
+            let _ = fs::remove_file(path);
+            spooled?;
+        }
+        export.file.flush()
+    }
+
+    fn render_spool<W: Write>(&self, out: &mut W, spool: BufWriter<File>) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut spool = spool.into_inner().map_err(|e| e.into_error())?;
+        spool.seek(SeekFrom::Start(0))?;
+        self.render_header(out)?;
+        let mut cells = BufReader::new(spool).split(0);
+        for number in 1..=self.rows {
+            let row = cells
+                .by_ref()
+                .take(self.columns.len())
+                .map(|cell| cell.map(|c| String::from_utf8_lossy(&c).into_owned()))
+                .collect::<io::Result<Vec<String>>>()?;
+            self.render_row(out, number, &row)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_hit_limit(&mut self) {
+        self.hit_limit = true;
+        self.chunks.push(Chunk::Banner);
+    }
+
+    pub fn hit_limit(&self) -> bool {
+        self.hit_limit
+    }
+
+    /// Number of rows kept for display, including ones dropped from the tail
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Called once the script has finished. Returns `true` if the buffer needs to be
+    /// re-rendered, i.e. in tail mode, where rows aren't shown until the end.
+    pub fn finish(&mut self) -> bool {
+        if !self.settings.tail || self.rows == 0 {
+            return false;
+        }
+        self.auto_widths = self.columns.iter().map(|c| c.name.width()).collect();
+        for (_, cells) in self.tail.iter() {
+            for (cell, auto_width) in cells.iter().zip(self.auto_widths.iter_mut()) {
+                *auto_width = cmp::max(*auto_width, cell.width());
+            }
+        }
+        true
+    }
+
+    /// Truncate a cell to its column's fixed width, or to `max_width` for auto-width
+    /// columns, and escape it for the renderer
+    fn prepare(&self, grid_width: i16, value: String) -> String {
+        match self.settings.renderer {
+            Renderer::Csv => value,
+            Renderer::Markdown => self.fit(grid_width, value).replace('|', "\\|"),
+            _ => self.fit(grid_width, value),
+        }
+    }
+
+    fn fit(&self, grid_width: i16, value: String) -> String {
+        let limit = if grid_width > 0 { grid_width as usize } else { self.settings.max_width };
+        if limit > 0 {
+            truncate_to_width(&value, limit)
+        } else {
+            value
+        }
+    }
+
+    pub fn render<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut header_written = false;
+        for chunk in self.chunks.iter() {
+            match *chunk {
+                Chunk::Text(ref text) => write!(out, "{}", text)?,
+                Chunk::Row(number, ref cells) => {
+                    if !header_written {
+                        self.render_header(out)?;
+                        header_written = true;
+                    }
+                    self.render_row(out, number, cells)?;
+                }
+                Chunk::Tail => self.render_tail(out)?,
+                Chunk::Banner => self.render_notice(out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Render only the most recently pushed chunk, for appending to an existing rendering
+    pub fn render_last<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self.chunks.last() {
+            Some(Chunk::Text(text)) => write!(out, "{}", text),
+            Some(Chunk::Row(number, cells)) => self.render_row(out, *number, cells),
+            Some(Chunk::Banner) => self.render_notice(out),
+            Some(Chunk::Tail) | None => Ok(()),
+        }
+    }
+
+    /// Why some rows aren't shown, if any were left out
+    pub fn notice(&self) -> Option<String> {
+        if self.hit_limit && self.settings.offset > 0 {
+            Some(format!("Hit limit of {} rows (skipped first {})", self.settings.limit, self.settings.offset))
+        } else if self.hit_limit {
+            Some(format!("Hit limit of {} rows", self.settings.limit))
+        } else if self.settings.tail && self.rows > self.tail.len() {
+            Some(format!("Showing last {} of {} rows", self.tail.len(), self.rows))
+        } else {
+            None
+        }
+    }
+
+    /// The notice is left out of CSV, which should stay parseable; it's logged instead
+    fn render_notice<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self.notice() {
+            Some(ref notice) if self.settings.renderer != Renderer::Csv => writeln!(out, "------\n{}", notice),
+            _ => Ok(()),
+        }
+    }
+
+    fn render_tail<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.render_header(out)?;
+        for &(number, ref cells) in self.tail.iter() {
+            self.render_row(out, number, cells)?;
+        }
+        self.render_notice(out)
+    }
+
+    /// Vim syntax rules for the rendered buffer, with the current column positions
+    pub fn syntax(&self) -> String {
+        let mut rules = vec![
+            "scriptencoding utf-8".to_string(),
+            "syntax clear".to_string(),
+            r"syntax match lividBanner /^Hit limit of \d\+ rows.*$/".to_string(),
+            r"syntax match lividBanner /^Showing last \d\+ of \d\+ rows$/".to_string(),
+            r"syntax match lividRule /^------$/".to_string(),
+            r"syntax match lividNegative /-\d[0-9.,e+-]*%\?/ contained".to_string(),
+        ];
+        match self.settings.renderer {
+            Renderer::Ascii | Renderer::Unicode | Renderer::Markdown => {
+                // Later rules take priority, so the header and rules are defined after rows
+                rules.push(r"syntax match lividRow /^[|│] .*[|│]$/ contains=lividBorder,@lividCells".to_string());
+                rules.push(r"syntax match lividHeader /^[|│].*\ze\n[+├|][-─:]/".to_string());
+                rules.push(r"syntax match lividRule /^[+├][-─+┼┤]\+$/".to_string());
+                rules.push(r"syntax match lividRule /^|[-:|]\+$/".to_string());
+                rules.push(r"syntax match lividBorder /[|│]/ contained".to_string());
+                // Cells are matched by virtual column, so wide characters line up
+                let mut start = 3;
+                for (_, column, width) in self.visible() {
+                    if width > 0 {
+                        let end = start + width - 1;
+                        rules.push(format!(
+                            r"syntax match {} /\%{}v.*\%{}v./ contained contains=lividNegative",
+                            syntax_group(column.cell_type),
+                            start,
+                            end
+                        ));
+                        rules.push(format!(
+                            r"syntax match lividEmpty /\%{}v \{{{}}}\ze [|│]/ contained",
+                            start, width
+                        ));
+                    }
+                    start += width + 3;
+                }
+                rules.push("syntax cluster lividCells contains=lividNumber,lividBool,lividText,lividEmpty".to_string());
+            }
+            Renderer::Expanded => {
+                rules.push(r"syntax match lividHeader /^-\[ RECORD \d\+ \]-*$/".to_string());
+                for (_, column, _) in self.visible() {
+                    let name = column.name.replace('\\', r"\\").replace('/', r"\/");
+                    rules.push(format!(r"syntax match lividField /^\V{}\m *\ze | /", name));
+                    rules.push(format!(
+                        r"syntax match {} /\%(^\V{}\m *| \)\@<=.*/ contains=lividNegative",
+                        syntax_group(column.cell_type),
+                        name
+                    ));
+                }
+            }
+            // There's nothing to tell the header apart from text printed by the script
+            Renderer::Csv => {}
+        }
+        rules.extend(
+            [
+                "hi def link lividHeader Title",
+                "hi def link lividRule Comment",
+                "hi def link lividBorder Comment",
+                "hi def link lividBanner WarningMsg",
+                "hi def link lividNumber Number",
+                "hi def link lividBool Boolean",
+                "hi def link lividText String",
+                "hi def link lividField Identifier",
+                "hi def link lividNegative Special",
+                "hi def lividEmpty ctermbg=darkgrey guibg=grey30",
+            ]
+            .iter()
+            .map(|s| s.to_string()),
+        );
+        rules.join("\n") + "\n"
+    }
+
+    /// Visible columns with their widths, and the indices of their cells
+    fn visible<'b>(&'b self) -> impl Iterator<Item = (usize, &'b GridColumn, usize)> + 'b {
+        self.columns
+            .iter()
+            .zip(self.auto_widths.iter())
+            .enumerate()
+            .filter_map(|(i, (column, auto_width))| column.width(*auto_width).map(|w| (i, column, w)))
+    }
+
+    fn render_header<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let names: Vec<String> = self.columns.iter().map(|c| c.name.clone()).collect();
+        match self.settings.renderer {
+            Renderer::Ascii => {
+                self.render_cells(out, &names, "|")?;
+                self.render_rule(out, ["+", "+", "+"], "-")
+            }
+            Renderer::Unicode => {
+                self.render_cells(out, &names, "│")?;
+                self.render_rule(out, ["├", "┼", "┤"], "─")
+            }
+            Renderer::Markdown => {
+                self.render_cells(out, &names, "|")?;
+                for (_, column, width) in self.visible() {
+                    match column.align {
+                        Align::Left => write!(out, "|{}", "-".repeat(width + 2))?,
+                        Align::Right => write!(out, "|{}:", "-".repeat(width + 1))?,
+                    }
+                }
+                writeln!(out, "|")
+            }
+            Renderer::Csv => self.render_csv(out, &names),
+            // Names are shown with every record
+            Renderer::Expanded => Ok(()),
+        }
+    }
+
+    fn render_row<W: Write>(&self, out: &mut W, number: usize, cells: &[String]) -> io::Result<()> {
+        match self.settings.renderer {
+            Renderer::Ascii | Renderer::Markdown => self.render_cells(out, cells, "|"),
+            Renderer::Unicode => self.render_cells(out, cells, "│"),
+            Renderer::Csv => self.render_csv(out, cells),
+            Renderer::Expanded => self.render_record(out, number, cells),
+        }
+    }
+
+    fn render_cells<W: Write>(&self, out: &mut W, cells: &[String], border: &str) -> io::Result<()> {
+        for (i, column, width) in self.visible() {
+            write_cell(out, &cells[i], width, column.align, border)?;
+        }
+        writeln!(out, "{}", border)
+    }
+
+    /// A horizontal line under the header; `joints` are the left, middle and right corners
+    fn render_rule<W: Write>(&self, out: &mut W, joints: [&str; 3], fill: &str) -> io::Result<()> {
+        for (n, (_, _, width)) in self.visible().enumerate() {
+            let joint = if n == 0 { joints[0] } else { joints[1] };
+            write!(out, "{}{}", joint, fill.repeat(width + 2))?;
+        }
+        writeln!(out, "{}", joints[2])
+    }
+
+    fn render_csv<W: Write>(&self, out: &mut W, cells: &[String]) -> io::Result<()> {
+        let line: Vec<String> = self.visible().map(|(i, _, _)| csv_quote(&cells[i])).collect();
+        writeln!(out, "{}", line.join(","))
+    }
+
+    fn render_record<W: Write>(&self, out: &mut W, number: usize, cells: &[String]) -> io::Result<()> {
+        let name_width = self.visible().map(|(_, c, _)| c.name.width()).max().unwrap_or(0);
+        let value_width = self.visible().map(|(_, _, w)| w).max().unwrap_or(0);
+        let title = format!("-[ RECORD {} ]", number);
+        let rule = (name_width + value_width + 3).saturating_sub(title.width());
+        writeln!(out, "{}{}", title, "-".repeat(rule))?;
+        for (i, column, _) in self.visible() {
+            let padding = " ".repeat(name_width - column.name.width());
+            writeln!(out, "{}{} | {}", column.name, padding, cells[i])?;
+        }
+        Ok(())
+    }
+}
+
+/// Write one grid cell, padded by display width
+fn write_cell<W: Write>(out: &mut W, value: &str, width: usize, align: Align, border: &str) -> io::Result<()> {
+    let padding = " ".repeat(cmp::max(width, value.width()) - value.width());
+    match align {
+        Align::Left => write!(out, "{} {}{} ", border, value, padding),
+        Align::Right => write!(out, "{} {}{} ", border, padding, value),
+    }
+}
+
+fn syntax_group(cell_type: CellType) -> &'static str {
+    match cell_type {
+        CellType::Text => "lividText",
+        CellType::Bool => "lividBool",
+        CellType::Long | CellType::Time | CellType::Double | CellType::Decimal => "lividNumber",
+    }
+}
+
+fn csv_quote(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use {CellFormat, OnError};
+
+    fn column(name: &str, cell_type: CellType) -> Column {
+        Column {
+            name: CString::new(name).unwrap(),
+            index: 0,
+            cell_type,
+            grid_width: 0,
+            on_error: OnError::Empty,
+            default_value: None,
+            scale: 0,
+            format: CellFormat::default(),
+        }
+    }
+
+    fn columns() -> Vec<Column> {
+        vec![column("name", CellType::Text), column("n", CellType::Long)]
+    }
+
+    fn row(name: &str, n: usize) -> Vec<String> {
+        vec![name.to_string(), n.to_string()]
+    }
+
+    /// Pass `rows` through the grid like a script would, until it's told to stop
+    fn buffer(settings: ViewSettings, rows: &[(&str, usize)]) -> OutputBuffer {
+        let mut buffer = OutputBuffer::new();
+        buffer.settings = settings;
+        for &(name, n) in rows {
+            match buffer.row_action() {
+                RowAction::Skip => {}
+                RowAction::Keep => {
+                    buffer.push_row(&columns(), row(name, n));
+                }
+                RowAction::Stop => {
+                    buffer.set_hit_limit();
+                    break;
+                }
+            }
+        }
+        buffer.finish();
+        buffer
+    }
+
+    fn rendered(buffer: &OutputBuffer) -> String {
+        let mut out = vec![];
+        buffer.render(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn settings(renderer: Renderer) -> ViewSettings {
+        ViewSettings {
+            renderer,
+            ..ViewSettings::default()
+        }
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_quote("plain"), "plain");
+        assert_eq!(csv_quote("a,b"), "\"a,b\"");
+        assert_eq!(csv_quote("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_quote("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_quote(""), "");
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        let mut settings = ViewSettings::default();
+        settings.set("limit", "5").unwrap();
+        settings.set("offset", "10").unwrap();
+        settings.set("tail", "yes").unwrap();
+        settings.set("precision", "3").unwrap();
+        settings.set("renderer", "markdown").unwrap();
+        assert_eq!(settings.limit, 5);
+        assert_eq!(settings.offset, 10);
+        assert!(settings.tail);
+        assert_eq!(settings.precision, Some(3));
+        assert_eq!(settings.renderer, Renderer::Markdown);
+
+        settings.set("precision", "none").unwrap();
+        assert_eq!(settings.precision, None);
+        assert!(settings.set("limit", "many").is_err());
+        assert!(settings.set("tail", "maybe").is_err());
+        assert!(settings.set("renderer", "html").is_err());
+        assert!(settings.set("colour", "red").is_err());
+    }
+
+    #[test]
+    fn offset_skips_rows_and_limit_stops() {
+        let settings = ViewSettings {
+            limit: 2,
+            offset: 3,
+            ..settings(Renderer::Csv)
+        };
+        let rows: Vec<(&str, usize)> = (1..=10).map(|n| ("x", n)).collect();
+        let buffer = buffer(settings, &rows);
+        assert!(buffer.hit_limit());
+        assert_eq!(buffer.rows(), 2);
+        // The notice would break the CSV, so it's only logged
+        assert_eq!(rendered(&buffer), "name,n\nx,4\nx,5\n");
+        assert_eq!(buffer.notice().unwrap(), "Hit limit of 2 rows (skipped first 3)");
+    }
+
+    /// Export `rows` with `renderer`, like a run would, and read back the file
+    fn exported(renderer: Renderer, rows: &[(&str, usize)]) -> String {
+        let dir = std::env::temp_dir().join(format!("livid-export-{}-{}", std::process::id(), renderer));
+        fs::create_dir_all(&dir).unwrap();
+        let mut buffer = OutputBuffer::new();
+        buffer.settings = settings(renderer);
+        buffer.start_export(&dir.join("out"), renderer, &dir.join("spool")).unwrap();
+        buffer.push_text("not exported\n");
+        for &(name, n) in rows {
+            buffer.row_action();
+            buffer.export_row(&columns(), row(name, n)).unwrap();
+        }
+        buffer.finish_export().unwrap();
+        assert!(!dir.join("spool").exists());
+        let out = fs::read_to_string(dir.join("out")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        out
+    }
+
+    #[test]
+    fn export_writes_only_the_grid() {
+        assert_eq!(exported(Renderer::Csv, &[("a,b", 1), ("c", 22)]), "name,n\n\"a,b\",1\nc,22\n");
+        // Padded rows are aligned to the widest one, even if it comes last
+        assert_eq!(
+            exported(Renderer::Markdown, &[("a", 1), ("bbbbbb", 22)]),
+            "| name   |  n |\n\
+             |--------|---:|\n\
+             | a      |  1 |\n\
+             | bbbbbb | 22 |\n"
+        );
+        assert_eq!(exported(Renderer::Ascii, &[]), "");
+    }
+
+    #[test]
+    fn tail_keeps_the_last_rows() {
+        let settings = ViewSettings {
+            limit: 2,
+            tail: true,
+            ..settings(Renderer::Ascii)
+        };
+        let buffer = buffer(settings, &[("a", 1), ("bbbbbb", 2), ("c", 3), ("d", 4)]);
+        assert!(!buffer.hit_limit());
+        assert_eq!(buffer.rows(), 4);
+        // Widths only count the rows that are left
+        assert_eq!(
+            rendered(&buffer),
+            "| name | n |\n\
+             +------+---+\n\
+             | c    | 3 |\n\
+             | d    | 4 |\n\
+             ------\nShowing last 2 of 4 rows\n"
+        );
+    }
+
+    #[test]
+    fn ascii_pads_by_display_width_and_aligns_by_type() {
+        let buffer = buffer(settings(Renderer::Ascii), &[("日本", 1), ("abc", 1000)]);
+        assert_eq!(
+            rendered(&buffer),
+            "| name |    n |\n\
+             +------+------+\n\
+             | 日本 |    1 |\n\
+             | abc  | 1000 |\n"
+        );
+    }
+
+    #[test]
+    fn max_width_truncates_auto_width_columns() {
+        let settings = ViewSettings {
+            max_width: 4,
+            ..settings(Renderer::Ascii)
+        };
+        let buffer = buffer(settings, &[("abcdefgh", 1)]);
+        assert!(rendered(&buffer).contains("| abc… |"));
+    }
+
+    #[test]
+    fn markdown_escapes_pipes() {
+        let buffer = buffer(settings(Renderer::Markdown), &[("a|b", 1)]);
+        assert_eq!(
+            rendered(&buffer),
+            "| name | n |\n\
+             |------|--:|\n\
+             | a\\|b | 1 |\n"
+        );
+    }
+
+    #[test]
+    fn csv_is_quoted_and_not_padded() {
+        let buffer = buffer(settings(Renderer::Csv), &[("x", 1), ("a, b", 22)]);
+        assert_eq!(rendered(&buffer), "name,n\nx,1\n\"a, b\",22\n");
+    }
+
+    #[test]
+    fn expanded_shows_one_field_per_line() {
+        let buffer = buffer(settings(Renderer::Expanded), &[("x", 1)]);
+        assert_eq!(rendered(&buffer), "-[ RECORD 1 ]\nname | x\nn    | 1\n");
+    }
+}
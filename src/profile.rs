@@ -0,0 +1,228 @@
+//! A summary of each input column, to see what's in a file before writing a script. It's
+//! printed by `livid profile` and shown in the output until the script is first saved.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+
+use unicode_width::UnicodeWidthStr;
+
+use super::sketches::Distinct;
+use super::{escape_invalid_utf8, Cell, CellType, Column, CsvInputFile, InputTable};
+
+/// Distinct values counted for the most common ones; values first seen after that many
+/// aren't counted, so a column of mostly unique values doesn't fill memory
+const TOP_COUNTED: usize = 100_000;
+const TOP_SHOWN: usize = 5;
+const EXAMPLES: usize = 3;
+/// Longest value shown, in columns
+const VALUE_WIDTH: usize = 24;
+
+/// How values of a column are ordered for the minimum and maximum
+#[derive(Clone, PartialEq, PartialOrd)]
+enum SortKey {
+    Number(i64),
+    Double(f64),
+    Text(Vec<u8>),
+}
+
+struct ColumnProfile {
+    name: String,
+    cell_type: String,
+    nulls: u64,
+    invalid: u64,
+    distinct: Distinct,
+    min: Option<(SortKey, String)>,
+    max: Option<(SortKey, String)>,
+    examples: Vec<String>,
+    counts: HashMap<String, u64>,
+    /// Whether values were left uncounted once `counts` was full
+    counts_full: bool,
+}
+
+impl ColumnProfile {
+    fn new(column: &Column) -> Self {
+        ColumnProfile {
+            name: escape_invalid_utf8(column.name.to_bytes()),
+            cell_type: column.type_str(),
+            nulls: 0,
+            invalid: 0,
+            distinct: Distinct::default(),
+            min: None,
+            max: None,
+            examples: vec![],
+            counts: HashMap::new(),
+            counts_full: false,
+        }
+    }
+
+    fn add(&mut self, cell: &Cell) {
+        if cell.empty {
+            self.nulls += 1;
+            return;
+        }
+        let (key, text) = unsafe {
+            let key = match cell.column.cell_type {
+                CellType::Text => SortKey::Text(CStr::from_ptr(cell.value.text.ptr).to_bytes().to_vec()),
+                CellType::Double => SortKey::Double(cell.value.double),
+                CellType::Bool => SortKey::Number(cell.value.boolean as i64),
+                CellType::Long => SortKey::Number(cell.value.long),
+                CellType::Time => SortKey::Number(cell.value.time),
+                CellType::Decimal => SortKey::Number(cell.value.decimal),
+            };
+            (key, cell.value.to_string(cell.column, false, None))
+        };
+        self.distinct.add_text(text.as_bytes());
+        if self.examples.len() < EXAMPLES && !self.examples.contains(&text) {
+            self.examples.push(text.clone());
+        }
+        if self.min.as_ref().is_none_or(|(min, _)| key < *min) {
+            self.min = Some((key.clone(), text.clone()));
+        }
+        if self.max.as_ref().is_none_or(|(max, _)| key > *max) {
+            self.max = Some((key, text.clone()));
+        }
+        if let Some(count) = self.counts.get_mut(&text) {
+            *count += 1;
+        } else if self.counts.len() < TOP_COUNTED {
+            self.counts.insert(text, 1);
+        } else {
+            self.counts_full = true;
+        }
+    }
+
+    /// The values seen more than once, most common first
+    fn most_common(&self) -> Vec<(&String, u64)> {
+        let mut top: Vec<_> = self.counts.iter().filter(|&(_, &count)| count > 1).map(|(v, &c)| (v, c)).collect();
+        // Ties are broken by value, so the output doesn't depend on hashing
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        top.truncate(TOP_SHOWN);
+        top
+    }
+}
+
+pub struct Profile {
+    rows: u64,
+    columns: Vec<ColumnProfile>,
+    /// Why the input ended early, if it did
+    read_error: Option<String>,
+}
+
+impl Profile {
+    /// Read every row of `input`, parsed as the types it inferred, then rewind it
+    pub fn read(input: &mut CsvInputFile) -> Profile {
+        let columns = input.input_columns().clone();
+        let mut profile = Profile {
+            rows: 0,
+            columns: columns.iter().map(ColumnProfile::new).collect(),
+            read_error: None,
+        };
+        input.set_output_columns(columns);
+        input.reset();
+        while let Some(row) = input.next() {
+            profile.rows += 1;
+            for (column, cell) in profile.columns.iter_mut().zip(row.iter()) {
+                column.add(cell);
+            }
+        }
+        // Values that don't parse are counted as empty too
+        for (column, &invalid) in profile.columns.iter_mut().zip(input.diagnostics.parse_failures.iter()) {
+            column.invalid = invalid as u64;
+            column.nulls = column.nulls.saturating_sub(column.invalid);
+        }
+        profile.read_error = input.reader.read_error.as_ref().map(|e| e.to_string());
+        input.reset();
+        profile
+    }
+}
+
+/// Shorten a value to `VALUE_WIDTH` columns
+fn clip(value: &str) -> String {
+    if value.width() <= VALUE_WIDTH {
+        return value.to_string();
+    }
+    let mut clipped = String::new();
+    for c in value.chars() {
+        if clipped.width() + c.to_string().width() >= VALUE_WIDTH {
+            break;
+        }
+        clipped.push(c);
+    }
+    clipped + "…"
+}
+
+/// Lines of cells, with each column padded to its widest cell; numbers are right-aligned
+fn write_table(f: &mut fmt::Formatter, rows: &[Vec<String>], right_align: &[bool]) -> fmt::Result {
+    let mut widths = vec![0; right_align.len()];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.width());
+        }
+    }
+    for row in rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            let padding = " ".repeat(widths[i] - cell.width());
+            if i > 0 {
+                line.push_str("  ");
+            }
+            if right_align[i] {
+                line.push_str(&padding);
+                line.push_str(cell);
+            } else {
+                line.push_str(cell);
+                line.push_str(&padding);
+            }
+        }
+        writeln!(f, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} rows, {} columns", self.rows, self.columns.len())?;
+        if let Some(ref error) = self.read_error {
+            writeln!(f, "The input ended early: {}", error)?;
+        }
+        writeln!(f)?;
+        let mut rows = vec![["column", "type", "empty", "invalid", "distinct", "min", "max", "examples"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()];
+        for c in self.columns.iter() {
+            let value = |v: &Option<(SortKey, String)>| v.as_ref().map(|(_, s)| clip(s)).unwrap_or_default();
+            rows.push(vec![
+                c.name.clone(),
+                c.cell_type.clone(),
+                c.nulls.to_string(),
+                c.invalid.to_string(),
+                format!("~{}", c.distinct.count().round()),
+                value(&c.min),
+                value(&c.max),
+                c.examples.iter().map(|e| clip(e)).collect::<Vec<_>>().join(", "),
+            ]);
+        }
+        write_table(f, &rows, &[false, false, true, true, true, false, false, false])?;
+
+        writeln!(f)?;
+        writeln!(f, "Most common values")?;
+        let mut rows = vec![];
+        for c in self.columns.iter() {
+            let top = c.most_common();
+            let mut values = if top.is_empty() {
+                "(none repeated)".to_string()
+            } else {
+                top.iter()
+                    .map(|&(value, count)| format!("{} ({})", clip(value), count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            if c.counts_full {
+                values += &format!(", counting the first {} distinct values", TOP_COUNTED);
+            }
+            rows.push(vec![c.name.clone(), values]);
+        }
+        write_table(f, &rows, &[false, false])
+    }
+}
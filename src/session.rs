@@ -0,0 +1,156 @@
+//! Running livid sessions. Each one has its own workspace, locked for as long as the
+//! session runs, and its own vim server name. Sessions are listed in a per-user
+//! directory so `livid list` and `livid attach` can find them from anywhere.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nix::fcntl::{flock, FlockArg};
+
+/// Workspace of the first session started in a directory
+pub const DEFAULT_WORKSPACE: &str = "./wkspace";
+
+#[derive(Debug)]
+pub struct Session {
+    pub pid: u32,
+    pub servername: String,
+    pub workspace: PathBuf,
+    pub input: PathBuf,
+    /// Held while the session runs; the lock is released when the process exits
+    lock: Option<File>,
+}
+
+impl Session {
+    /// Start a session in `./wkspace`, or in `./wkspace-<pid>` if another session is
+    /// already using it. A session that resumes the work in `./wkspace` can't move.
+    pub fn create(input: &Path, resume: bool) -> io::Result<Session> {
+        let pid = process::id();
+        let mut workspace = PathBuf::from(DEFAULT_WORKSPACE);
+        let mut lock = lock_workspace(&workspace)?;
+        if lock.is_none() && !resume {
+            workspace = PathBuf::from(format!("./wkspace-{}", pid));
+            lock = lock_workspace(&workspace)?;
+        }
+        let lock = lock.ok_or_else(|| io::Error::other(format!("{:?} is locked", workspace)))?;
+        lock.set_len(0)?;
+        writeln!(&lock, "{}", pid)?;
+
+        let session = Session {
+            pid,
+            // vim upper-cases X11 server names, so use one that survives that
+            servername: format!("LIVID-{}", pid),
+            workspace: fs::canonicalize(&workspace)?,
+            input: fs::canonicalize(input).unwrap_or_else(|_| input.to_path_buf()),
+            lock: Some(lock),
+        };
+        fs::create_dir_all(registry_dir())?;
+        fs::write(session.entry_path(), session.to_entry())?;
+        Ok(session)
+    }
+
+    /// Sessions that are still running; entries left behind by ones that weren't shut
+    /// down cleanly are removed
+    pub fn list() -> io::Result<Vec<Session>> {
+        let entries = match fs::read_dir(registry_dir()) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut sessions = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            match fs::read_to_string(&path).ok().and_then(|s| Session::from_entry(&s)) {
+                Some(session) if session.is_running() => sessions.push(session),
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        sessions.sort_by_key(|s| s.pid);
+        Ok(sessions)
+    }
+
+    /// Find a running session by pid or server name, or the only one if `name` is `None`
+    pub fn find(name: Option<&str>) -> Result<Session, String> {
+        let sessions = Session::list().map_err(|e| e.to_string())?;
+        let mut matches: Vec<Session> = match name {
+            Some(name) => sessions
+                .into_iter()
+                .filter(|s| s.pid.to_string() == name || s.servername.eq_ignore_ascii_case(name))
+                .collect(),
+            None => sessions,
+        };
+        match matches.len() {
+            0 => Err("no matching livid session is running".to_string()),
+            1 => Ok(matches.remove(0)),
+            _ => Err("more than one livid session is running; pick one from `livid list`".to_string()),
+        }
+    }
+
+    /// Take the session out of the list; the workspace is left for inspection
+    pub fn end(&mut self) {
+        let _ = fs::remove_file(self.entry_path());
+        self.lock.take();
+    }
+
+    fn is_running(&self) -> bool {
+        // If the lock can be taken, the session that held it is gone
+        match File::open(self.workspace.join("lock")) {
+            Ok(lock) => flock(lock.as_raw_fd(), FlockArg::LockSharedNonblock).is_err(),
+            Err(_) => false,
+        }
+    }
+
+    fn entry_path(&self) -> PathBuf {
+        registry_dir().join(self.pid.to_string())
+    }
+
+    fn to_entry(&self) -> String {
+        format!(
+            "pid={}\nservername={}\nworkspace={}\ninput={}\n",
+            self.pid,
+            self.servername,
+            self.workspace.display(),
+            self.input.display()
+        )
+    }
+
+    fn from_entry(entry: &str) -> Option<Session> {
+        let field = |key: &str| {
+            entry
+                .lines()
+                .find(|l| l.starts_with(key) && l[key.len()..].starts_with('='))
+                .map(|l| l[key.len() + 1..].to_string())
+        };
+        Some(Session {
+            pid: field("pid")?.parse().ok()?,
+            servername: field("servername")?,
+            workspace: PathBuf::from(field("workspace")?),
+            input: PathBuf::from(field("input")?),
+            lock: None,
+        })
+    }
+}
+
+/// Where running sessions are listed
+fn registry_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("livid"),
+        None => env::temp_dir().join(format!("livid-{}", nix::unistd::getuid())),
+    }
+}
+
+/// Create `workspace` and lock it. Returns `None` if another session holds the lock.
+fn lock_workspace(workspace: &Path) -> io::Result<Option<File>> {
+    fs::create_dir_all(workspace)?;
+    let lock = OpenOptions::new().write(true).create(true).truncate(false).open(workspace.join("lock"))?;
+    match flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(Some(lock)),
+        Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => Ok(None),
+        Err(e) => Err(io::Error::other(e.to_string())),
+    }
+}
@@ -0,0 +1,355 @@
+//! Approximate statistics for scripts over more rows than they'd want to keep: t-digests
+//! for quantiles and HyperLogLog counters for distinct values. Like maps, they're owned
+//! by the run and freed when `run` returns.
+
+use std::collections::hash_map::DefaultHasher;
+use std::f64;
+use std::f64::consts::PI;
+use std::hash::{Hash, Hasher};
+use std::os::raw::c_char;
+
+use super::{const_char_cstr, LividApi};
+
+/// Bounds the number of centroids, about `COMPRESSION / 2` once merged; higher is more
+/// accurate and slower
+const COMPRESSION: f64 = 100.0;
+/// Values collected before they're merged into the centroids
+const DIGEST_BUFFER: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// A merging t-digest: values are buffered, then sorted into centroids that are small
+/// near the tails, so extreme quantiles stay accurate
+pub struct Digest {
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Digest {
+    fn default() -> Self {
+        Digest {
+            centroids: vec![],
+            buffer: Vec::with_capacity(DIGEST_BUFFER),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+/// The scale function: centroids may span at most 1 unit of `k`
+fn scale(q: f64) -> f64 {
+    COMPRESSION / (2.0 * PI) * (2.0 * q - 1.0).asin()
+}
+
+fn scale_inverse(k: f64) -> f64 {
+    if k >= COMPRESSION / 4.0 {
+        1.0
+    } else {
+        ((k * 2.0 * PI / COMPRESSION).sin() + 1.0) / 2.0
+    }
+}
+
+fn lerp(from: f64, to: f64, t: f64) -> f64 {
+    from + (to - from) * t.clamp(0.0, 1.0)
+}
+
+impl Digest {
+    /// Infinities are ignored like NaN: they'd turn the means of centroids into NaN
+    fn add(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(value);
+        if self.buffer.len() >= DIGEST_BUFFER {
+            self.merge();
+        }
+    }
+
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut points = self.centroids.split_off(0);
+        points.extend(self.buffer.drain(..).map(|mean| Centroid { mean, weight: 1.0 }));
+        points.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+
+        let total: f64 = points.iter().map(|c| c.weight).sum();
+        let mut before = 0.0;
+        let mut limit = scale_inverse(scale(0.0) + 1.0) * total;
+        let mut current = points[0];
+        for &point in &points[1..] {
+            if before + current.weight + point.weight <= limit {
+                current.weight += point.weight;
+                current.mean += (point.mean - current.mean) * point.weight / current.weight;
+            } else {
+                before += current.weight;
+                self.centroids.push(current);
+                limit = scale_inverse(scale(before / total) + 1.0) * total;
+                current = point;
+            }
+        }
+        self.centroids.push(current);
+    }
+
+    /// Interpolates between centroid means, with each mean at the middle of its weight, so
+    /// while few values have been added this matches the exact linear-interpolated quantile
+    fn quantile(&mut self, q: f64) -> f64 {
+        self.merge();
+        if self.centroids.is_empty() || q.is_nan() {
+            return f64::NAN;
+        }
+        let total = self.count as f64;
+        let target = q.clamp(0.0, 1.0) * (total - 1.0) + 0.5;
+        let mut before = 0.0;
+        let mut previous: Option<(f64, f64)> = None;
+        for c in &self.centroids {
+            let middle = before + c.weight / 2.0;
+            if target < middle {
+                let value = match previous {
+                    Some((mean, position)) => lerp(mean, c.mean, (target - position) / (middle - position)),
+                    None => lerp(self.min, c.mean, (target - 0.5) / (middle - 0.5)),
+                };
+                return value.clamp(self.min, self.max);
+            }
+            previous = Some((c.mean, middle));
+            before += c.weight;
+        }
+        let (mean, position) = previous.unwrap();
+        if total - 0.5 <= position {
+            return self.max;
+        }
+        lerp(mean, self.max, (target - position) / (total - 0.5 - position))
+    }
+}
+
+/// log2 of the number of HyperLogLog registers; the standard error is `1.04 / sqrt(2^p)`,
+/// under 1% for 14
+const DISTINCT_PRECISION: u32 = 14;
+
+/// A HyperLogLog counter of distinct strings and numbers
+pub struct Distinct {
+    registers: Vec<u8>,
+}
+
+impl Default for Distinct {
+    fn default() -> Self {
+        Distinct {
+            registers: vec![0; 1 << DISTINCT_PRECISION],
+        }
+    }
+}
+
+impl Distinct {
+    fn add(&mut self, hash: u64) {
+        let index = (hash >> (64 - DISTINCT_PRECISION)) as usize;
+        // The bit set at the end bounds the rank when the rest of the hash is all zeros
+        let rest = (hash << DISTINCT_PRECISION) | (1 << (DISTINCT_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn add_text(&mut self, text: &[u8]) {
+        self.add(hash_text(text))
+    }
+
+    pub fn count(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-i32::from(r))).sum();
+        let estimate = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        // Linear counting is more accurate while many registers are still empty
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+fn hash_text(text: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (0u8, text).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Strings and numbers hash differently, so "1" and 1 are distinct
+unsafe fn hash_key(text_key: bool, text: *const c_char, number: i64) -> u64 {
+    if text_key {
+        return hash_text(if text.is_null() { b"" } else { const_char_cstr(text).to_bytes() });
+    }
+    let mut hasher = DefaultHasher::new();
+    (1u8, number).hash(&mut hasher);
+    hasher.finish()
+}
+
+pub extern "C" fn livid_api_raw_digest_new<'a>(api: *mut LividApi<'a>) -> *mut Digest {
+    unsafe {
+        let digests = &mut (*api).digests;
+        digests.push(Box::default());
+        &mut **digests.last_mut().unwrap()
+    }
+}
+
+pub extern "C" fn livid_api_raw_digest_add<'a>(_api: *mut LividApi<'a>, digest: *mut Digest, value: f64) {
+    unsafe { (&mut *digest).add(value) }
+}
+
+/// The approximate `q` quantile, between 0 and 1, or NaN if nothing has been added
+pub extern "C" fn livid_api_raw_digest_quantile<'a>(_api: *mut LividApi<'a>, digest: *mut Digest, q: f64) -> f64 {
+    unsafe { (&mut *digest).quantile(q) }
+}
+
+pub extern "C" fn livid_api_raw_digest_count<'a>(_api: *mut LividApi<'a>, digest: *const Digest) -> u64 {
+    unsafe { (&*digest).count }
+}
+
+pub extern "C" fn livid_api_raw_distinct_new<'a>(api: *mut LividApi<'a>) -> *mut Distinct {
+    unsafe {
+        let distincts = &mut (*api).distincts;
+        distincts.push(Box::default());
+        &mut **distincts.last_mut().unwrap()
+    }
+}
+
+/// Count the string `text` if `text_key` is set, with NULL counted as "", otherwise
+/// `number`
+pub extern "C" fn livid_api_raw_distinct_add<'a>(
+    _api: *mut LividApi<'a>,
+    distinct: *mut Distinct,
+    text_key: bool,
+    text: *const c_char,
+    number: i64,
+) {
+    unsafe { (&mut *distinct).add(hash_key(text_key, text, number)) }
+}
+
+pub extern "C" fn livid_api_raw_distinct_count<'a>(_api: *mut LividApi<'a>, distinct: *const Distinct) -> i64 {
+    unsafe { (&*distinct).count().round() as i64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    /// Reproducible values spread over [0, 1)
+    fn values(n: usize) -> Vec<f64> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..n)
+            .map(|_| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                (state >> 11) as f64 / (1u64 << 53) as f64
+            }).collect()
+    }
+
+    /// The linearly interpolated quantile of sorted values
+    fn exact_quantile(sorted: &[f64], q: f64) -> f64 {
+        let position = q * (sorted.len() - 1) as f64;
+        let below = position.floor() as usize;
+        let above = position.ceil() as usize;
+        sorted[below] + (sorted[above] - sorted[below]) * (position - below as f64)
+    }
+
+    #[test]
+    fn few_values_give_exact_quantiles() {
+        let mut digest = Digest::default();
+        for &value in &[5.0, 1.0, 4.0, 2.0, 3.0] {
+            digest.add(value);
+        }
+        let sorted = [1.0, 2.0, 3.0, 4.0, 5.0];
+        for &q in &[0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 1.0] {
+            assert!((digest.quantile(q) - exact_quantile(&sorted, q)).abs() < 1e-9, "q = {}", q);
+        }
+        assert!(Digest::default().quantile(0.5).is_nan());
+    }
+
+    #[test]
+    fn quantiles_are_within_one_percent_of_rank() {
+        let mut digest = Digest::default();
+        let mut sorted = values(100_000);
+        for &value in &sorted {
+            digest.add(value);
+        }
+        sorted.sort_by(f64::total_cmp);
+        assert_eq!(digest.count, 100_000);
+        assert_eq!(digest.quantile(0.0), sorted[0]);
+        assert_eq!(digest.quantile(1.0), sorted[sorted.len() - 1]);
+        for &q in &[0.001, 0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 0.999] {
+            let estimate = digest.quantile(q);
+            let rank = sorted.partition_point(|&v| v < estimate) as f64 / sorted.len() as f64;
+            assert!((rank - q).abs() < 0.01, "q = {}: {} is at rank {}", q, estimate, rank);
+            // The values are uniform, so the error in value is about the error in rank
+            assert!((estimate - exact_quantile(&sorted, q)).abs() < 0.01, "q = {}", q);
+        }
+    }
+
+    #[test]
+    fn nan_and_infinities_are_ignored() {
+        let mut digest = Digest::default();
+        for (i, value) in values(5000).into_iter().enumerate() {
+            digest.add(value);
+            if i % 100 == 0 {
+                digest.add(f64::INFINITY);
+                digest.add(f64::NEG_INFINITY);
+                digest.add(f64::NAN);
+            }
+        }
+        assert_eq!(digest.count, 5000);
+        let median = digest.quantile(0.5);
+        assert!((median - 0.5).abs() < 0.05, "median {}", median);
+        assert!(digest.quantile(1.0) < 1.0);
+    }
+
+    #[test]
+    fn distinct_counts_are_close() {
+        for &n in &[0, 1, 100, 10_000, 200_000] {
+            let mut distinct = Distinct::default();
+            for i in 0..n {
+                // Each value is added twice, which mustn't count
+                distinct.add_text(format!("user{}", i).as_bytes());
+                distinct.add_text(format!("user{}", i).as_bytes());
+            }
+            let error = (distinct.count() - n as f64).abs() / (n as f64).max(1.0);
+            // Three standard errors
+            assert!(error < 0.025, "{} counted as {}", n, distinct.count());
+        }
+    }
+
+    #[test]
+    fn text_and_numbers_are_distinct() {
+        let one = CString::new("1").unwrap();
+        let mut distinct = Distinct::default();
+        unsafe {
+            distinct.add(hash_key(true, one.as_ptr(), 0));
+            distinct.add(hash_key(false, ptr::null(), 1));
+            distinct.add(hash_key(false, ptr::null(), 1));
+        }
+        assert_eq!(distinct.count().round(), 2.0);
+    }
+
+    #[test]
+    fn null_text_is_empty_text() {
+        let empty = CString::default();
+        let mut distinct = Distinct::default();
+        unsafe {
+            distinct.add(hash_key(true, ptr::null(), 0));
+            distinct.add(hash_key(true, empty.as_ptr(), 0));
+            distinct.add(hash_key(false, ptr::null(), 0));
+        }
+        assert_eq!(distinct.count().round(), 2.0);
+    }
+}
@@ -0,0 +1,135 @@
+//! String helpers for scripts: regular expressions, splitting, case conversion and
+//! trimming. Strings returned to the script are copied into the run's arena.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_char;
+use std::ptr;
+
+use regex::bytes::Regex;
+
+use super::{const_char_cstr, split_on, LividApi};
+
+/// Regular expressions compiled during a run, by pattern. Patterns that don't compile
+/// are kept as `None`, so the error is only shown once.
+#[derive(Default)]
+pub struct RegexCache {
+    compiled: HashMap<Vec<u8>, Option<Regex>>,
+}
+
+impl RegexCache {
+    fn get(&mut self, pattern: &CStr) -> Option<&Regex> {
+        self.compiled
+            .entry(pattern.to_bytes().to_vec())
+            .or_insert_with(|| {
+                let compiled = pattern
+                    .to_str()
+                    .map_err(|e| e.to_string())
+                    .and_then(|p| Regex::new(p).map_err(|e| e.to_string()));
+                match compiled {
+                    Ok(regex) => Some(regex),
+                    Err(e) => {
+                        println!("Invalid regex {:?}: {}", pattern, e);
+                        None
+                    }
+                }
+            }).as_ref()
+    }
+}
+
+/// Values of `conversion` for `api->convert`, matching `enum conversion` in livid.h
+const CONVERT_UPPER: i8 = 0;
+const CONVERT_LOWER: i8 = 1;
+const CONVERT_TRIM: i8 = 2;
+
+pub extern "C" fn livid_api_raw_regex_match<'a>(api: *mut LividApi<'a>, pattern: *const c_char, text: *const c_char) -> i8 {
+    unsafe {
+        let api = &mut (*api);
+        match api.regexes.get(const_char_cstr(pattern)) {
+            Some(regex) => regex.is_match(const_char_cstr(text).to_bytes()) as i8,
+            None => -1,
+        }
+    }
+}
+
+pub extern "C" fn livid_api_raw_regex_capture<'a>(
+    api: *mut LividApi<'a>,
+    pattern: *const c_char,
+    text: *const c_char,
+    group: usize,
+) -> *const c_char {
+    unsafe {
+        let api = &mut (*api);
+        let regex = match api.regexes.get(const_char_cstr(pattern)) {
+            Some(regex) => regex,
+            None => return ptr::null(),
+        };
+        match regex.captures(const_char_cstr(text).to_bytes()).and_then(|c| c.get(group)) {
+            Some(capture) => api.arena.copy(capture.as_bytes()),
+            None => ptr::null(),
+        }
+    }
+}
+
+pub extern "C" fn livid_api_raw_regex_replace<'a>(
+    api: *mut LividApi<'a>,
+    pattern: *const c_char,
+    text: *const c_char,
+    replacement: *const c_char,
+) -> *const c_char {
+    unsafe {
+        let api = &mut (*api);
+        let regex = match api.regexes.get(const_char_cstr(pattern)) {
+            Some(regex) => regex,
+            None => return ptr::null(),
+        };
+        let replaced = regex.replace_all(const_char_cstr(text).to_bytes(), const_char_cstr(replacement).to_bytes());
+        api.arena.copy(&replaced)
+    }
+}
+
+pub extern "C" fn livid_api_raw_split<'a>(
+    api: *mut LividApi<'a>,
+    text: *const c_char,
+    separator: *const c_char,
+    count_out: *mut usize,
+) -> *const *const c_char {
+    unsafe {
+        let api = &mut (*api);
+        let text = const_char_cstr(text).to_bytes();
+        let separator = const_char_cstr(separator).to_bytes();
+        let parts: Vec<&[u8]> = if separator.is_empty() {
+            text.split(u8::is_ascii_whitespace).filter(|p| !p.is_empty()).collect()
+        } else {
+            split_on(text, separator)
+        };
+        // NULL-terminated, so the count is optional
+        let array = api.arena.alloc((parts.len() + 1) * mem::size_of::<*const c_char>()) as *mut *const c_char;
+        for (i, part) in parts.iter().enumerate() {
+            array.add(i).write(api.arena.copy(part));
+        }
+        if !count_out.is_null() {
+            count_out.write(parts.len());
+        }
+        array
+    }
+}
+
+pub extern "C" fn livid_api_raw_convert<'a>(api: *mut LividApi<'a>, text: *const c_char, conversion: i8) -> *const c_char {
+    unsafe {
+        let api = &mut (*api);
+        let bytes = const_char_cstr(text).to_bytes();
+        // Text that isn't UTF-8 is converted as ASCII
+        let converted = match (std::str::from_utf8(bytes), conversion) {
+            (Ok(s), CONVERT_UPPER) => s.to_uppercase().into_bytes(),
+            (Ok(s), CONVERT_LOWER) => s.to_lowercase().into_bytes(),
+            (Ok(s), CONVERT_TRIM) => s.trim().as_bytes().to_vec(),
+            (Err(_), CONVERT_UPPER) => bytes.to_ascii_uppercase(),
+            (Err(_), CONVERT_LOWER) => bytes.to_ascii_lowercase(),
+            (Err(_), CONVERT_TRIM) => bytes.trim_ascii().to_vec(),
+            _ => return ptr::null(),
+        };
+        api.arena.copy(&converted)
+    }
+}
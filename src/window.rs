@@ -0,0 +1,266 @@
+//! Rows around the current one, for window functions in scripts: earlier rows kept with
+//! `api_history` and read with `api_lag`, later rows read ahead with `api_lead`, and
+//! state kept per partition key.
+//!
+//! Rows from the input point into its buffers, which may be reused once the next row is
+//! read, so rows that are kept have their text copied.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::c_char;
+use std::ptr;
+
+use super::maps::{key, Key};
+use super::{Cell, CellType, CellValue, CStrPtr, CsvInputFile, InputTable, LividApi};
+
+/// A row as `api_next` returns it, owning its text
+pub struct SavedRow {
+    values: Vec<CellValue<'static>>,
+    empty: Vec<i8>,
+    /// Backs the text values, which point into it
+    _text: Vec<CString>,
+    line: usize,
+    error: Option<CString>,
+}
+
+impl SavedRow {
+    /// The next row of `input`, if there is one
+    fn read(input: &mut CsvInputFile) -> Option<SavedRow> {
+        // `next` ties the borrow to the input's lifetime; the row is copied before it's
+        // used again
+        let input: *mut CsvInputFile = input;
+        unsafe {
+            let cells = (*input).next()?;
+            let mut row = SavedRow::new(&cells);
+            row.line = (*input).current_line;
+            row.error = (*input).diagnostics.row_error.clone();
+            Some(row)
+        }
+    }
+
+    fn new(cells: &[Cell]) -> SavedRow {
+        let mut text = vec![];
+        let values = cells
+            .iter()
+            .map(|cell| unsafe {
+                if cell.column.cell_type != CellType::Text || cell.value.text.ptr.is_null() {
+                    // Nothing else borrows from the input
+                    return mem::transmute::<CellValue, CellValue<'static>>(cell.value);
+                }
+                let copy = CStr::from_ptr(cell.value.text.ptr).to_owned();
+                // The string's buffer doesn't move when `copy` does
+                let value = CellValue {
+                    text: CStrPtr {
+                        ptr: copy.as_ptr(),
+                        phantom: PhantomData,
+                    },
+                };
+                text.push(copy);
+                value
+            }).collect();
+        SavedRow {
+            values,
+            empty: cells.iter().map(|c| c.empty as i8).collect(),
+            _text: text,
+            line: 0,
+            error: None,
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn error(&self) -> Option<&CString> {
+        self.error.as_ref()
+    }
+
+    pub unsafe fn write<'a>(&self, row_out: *mut CellValue<'a>, empty_out: *mut i8) {
+        ptr::copy_nonoverlapping(self.values.as_ptr(), row_out, self.values.len());
+        ptr::copy_nonoverlapping(self.empty.as_ptr(), empty_out, self.empty.len());
+    }
+}
+
+#[derive(Default)]
+pub struct Window {
+    /// Set once the script asks for history or reads ahead; rows are only copied after that
+    active: bool,
+    /// Rows kept before the current one
+    history_len: usize,
+    /// The current row, then the ones before it
+    history: VecDeque<SavedRow>,
+    /// Rows read by `api_lead` that `api_next` hasn't returned yet
+    ahead: VecDeque<SavedRow>,
+    /// The row `api_next` returned last while the window wasn't active, still pointing
+    /// into the input, so it can become the current row once it is
+    returned: Vec<CellValue<'static>>,
+    returned_empty: Vec<i8>,
+}
+
+impl Window {
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// The row `api_next` returned last
+    pub fn current(&self) -> Option<&SavedRow> {
+        self.history.front()
+    }
+
+    /// Note the row `api_next` returned without the window, or that there was none
+    pub fn returned(&mut self, cells: &[Cell]) {
+        self.returned.clear();
+        self.returned_empty.clear();
+        for cell in cells {
+            // Only read back by `activate`, before the input reads another row
+            self.returned.push(unsafe { mem::transmute::<CellValue, CellValue<'static>>(cell.value) });
+            self.returned_empty.push(cell.empty as i8);
+        }
+    }
+
+    /// Start copying rows. The current row was read before, so it's copied now, for
+    /// `api_lag(api, 0, ...)` and so `api_lead` doesn't read over it.
+    fn activate(&mut self, input: &CsvInputFile) {
+        if self.active {
+            return;
+        }
+        self.active = true;
+        if self.returned.is_empty() {
+            return;
+        }
+        let cells: Vec<Cell> = input
+            .output_columns()
+            .iter()
+            .zip(self.returned.iter().zip(self.returned_empty.iter()))
+            .map(|(column, (&value, &empty))| Cell {
+                column,
+                empty: empty != 0,
+                value,
+            }).collect();
+        let mut row = SavedRow::new(&cells);
+        row.line = input.current_line;
+        row.error = input.diagnostics.row_error.clone();
+        self.history.push_front(row);
+        self.returned.clear();
+        self.returned_empty.clear();
+    }
+
+    /// The next row, from those read ahead or else the input, which becomes the current one
+    pub fn next(&mut self, input: &mut CsvInputFile) -> Option<&SavedRow> {
+        let row = self.ahead.pop_front().or_else(|| SavedRow::read(input))?;
+        self.history.push_front(row);
+        self.history.truncate(self.history_len + 1);
+        self.history.front()
+    }
+}
+
+/// Per-key memory for the script, in the order keys were first seen
+#[derive(Default)]
+pub struct Partition {
+    states: Vec<(Key, *mut u8)>,
+    index: HashMap<Key, usize>,
+}
+
+pub extern "C" fn livid_api_raw_history<'a>(api: *mut LividApi<'a>, rows: usize) {
+    unsafe {
+        let api = &mut (*api);
+        api.window.activate(api.input);
+        api.window.history_len = rows;
+    }
+}
+
+/// Fill `row_out` with the row `k` rows before the current one; 0 if it wasn't kept
+pub extern "C" fn livid_api_raw_lag<'a>(api: *mut LividApi<'a>, k: usize, row_out: *mut CellValue<'a>, empty_out: *mut i8) -> i8 {
+    unsafe {
+        match (*api).window.history.get(k) {
+            Some(row) => {
+                row.write(row_out, empty_out);
+                1
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Fill `row_out` with the row `k` rows after the current one, reading ahead as far as
+/// needed; 0 past the end of the input
+pub extern "C" fn livid_api_raw_lead<'a>(api: *mut LividApi<'a>, k: usize, row_out: *mut CellValue<'a>, empty_out: *mut i8) -> i8 {
+    unsafe {
+        let api = &mut (*api);
+        let window = &mut api.window;
+        window.activate(api.input);
+        if k == 0 {
+            return livid_api_raw_lag(api, 0, row_out, empty_out);
+        }
+        while window.ahead.len() < k {
+            match SavedRow::read(api.input) {
+                Some(row) => window.ahead.push_back(row),
+                None => return 0,
+            }
+        }
+        window.ahead[k - 1].write(row_out, empty_out);
+        1
+    }
+}
+
+pub extern "C" fn livid_api_raw_partition_new<'a>(api: *mut LividApi<'a>) -> *mut Partition {
+    unsafe {
+        let partitions = &mut (*api).partitions;
+        partitions.push(Box::default());
+        &mut **partitions.last_mut().unwrap()
+    }
+}
+
+/// The state of `size` bytes for a key, zeroed and allocated in the arena the first time
+/// the key is seen. The key is read like `api->map_update`'s.
+pub extern "C" fn livid_api_raw_partition_state<'a>(
+    api: *mut LividApi<'a>,
+    partition: *mut Partition,
+    text_key: bool,
+    text: *const c_char,
+    number: i64,
+    size: usize,
+) -> *mut u8 {
+    unsafe {
+        let partition = &mut *partition;
+        let key = key(text_key, text, number);
+        if let Some(&i) = partition.index.get(&key) {
+            return partition.states[i].1;
+        }
+        let state = (*api).arena.alloc(size);
+        partition.index.insert(key.clone(), partition.states.len());
+        partition.states.push((key, state));
+        state
+    }
+}
+
+/// The state of the `index`th key, with the key in `*text_out` or `*number_out` like
+/// `api->map_entry`; NULL past the end
+pub extern "C" fn livid_api_raw_partition_entry<'a>(
+    _api: *mut LividApi<'a>,
+    partition: *const Partition,
+    index: usize,
+    text_out: *mut *const c_char,
+    number_out: *mut i64,
+) -> *mut u8 {
+    unsafe {
+        let partition = &*partition;
+        let (key, state) = match partition.states.get(index) {
+            Some(&(ref key, state)) => (key, state),
+            None => return ptr::null_mut(),
+        };
+        let (text, number) = match *key {
+            Key::Text(ref text) => (text.as_ptr(), 0),
+            Key::Number(number) => (ptr::null(), number),
+        };
+        if !text_out.is_null() {
+            text_out.write(text);
+        }
+        if !number_out.is_null() {
+            number_out.write(number);
+        }
+        state
+    }
+}